@@ -7,11 +7,16 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also compiles the HTML templates under `src/templates` into Rust
+//! render functions, see `compile_templates`.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -33,4 +38,512 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
-}
\ No newline at end of file
+
+    let templates = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("src/templates");
+    println!("cargo:rerun-if-changed={}", templates.display());
+    compile_templates(&templates, &out.join("templates.rs"));
+}
+
+// Every `.html` file under src/templates becomes a module of `template::compiled` holding a
+// `Context` struct with one field per name the template uses and a `render_async` function
+// writing the page to an async writer in one pass.
+// `partials/sign-up.html` becomes `compiled::partials::sign_up`.
+//
+//   {{name}}                         the `&[u8]` field, escaped for the spot it sits in
+//   {{{name}}}                       the same without escaping, for trusted markup
+//   {{#if name}}..{{/if name}}       rendered when the `bool` field is set
+//   {{#else name}}..{{/else name}}   rendered when it isn't
+//   {{#for item in items}}..{{/for item in items}}
+//                                    repeated for every element of the `&[ItemsItem]` field,
+//                                    with `{{item.field}}` reaching into the element
+//   {{> partials/name}}              partials/name.html in place, its names joining the context
+//   {{#extends layouts/base}}        as the first tag, renders layouts/base.html instead, with
+//                                    the `{{#block name}}..{{/block name}}` that follow taking the
+//                                    place of the layout's blocks of the same name
+//
+// Closing tags may leave out the name. Malformed templates fail the build.
+fn compile_templates(root: &Path, output: &Path) {
+    let mut files = Vec::new();
+    collect_templates(root, &mut files);
+    files.sort();
+
+    // Every template by name, along with the file it came from
+    let mut templates = BTreeMap::new();
+    for file in &files {
+        let relative = file.strip_prefix(root).unwrap();
+        let source = fs::read_to_string(file).unwrap_or_else(|e| panic!("{}: {}", file.display(), e));
+        templates.insert(template_name(relative), (relative.display().to_string(), source));
+    }
+
+    let mut tree = ModuleTree::default();
+    for file in &files {
+        let relative = file.strip_prefix(root).unwrap();
+        let code = compile_template(&template_name(relative), &templates);
+
+        let mut node = &mut tree;
+        let components: Vec<String> = relative.iter().map(|c| module_name(&c.to_string_lossy())).collect();
+        for directory in &components[..components.len() - 1] {
+            node = node.modules.entry(directory.clone()).or_default();
+        }
+        let name = components.last().unwrap().clone();
+        if node.templates.insert(name.clone(), code).is_some() || node.modules.contains_key(&name) {
+            panic!("{}: more than one template maps to the module {}", relative.display(), name);
+        }
+    }
+
+    let mut code = String::from("// Generated by build.rs from src/templates, edit the templates instead\n");
+    tree.write(&mut code);
+    fs::write(output, code).unwrap();
+}
+
+fn collect_templates(directory: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_templates(&path, files);
+        } else if path.extension().map_or(false, |extension| extension == "html") {
+            files.push(path);
+        }
+    }
+}
+
+// "partials/sign-up.html" -> "partials/sign-up", as includes and layouts name it
+fn template_name(relative: &Path) -> String {
+    let components: Vec<String> = relative.iter().map(|c| c.to_string_lossy().into_owned()).collect();
+    let name = components.join("/");
+    name.strip_suffix(".html").unwrap_or(&name).to_string()
+}
+
+// "sign-up.html" -> "sign_up"
+fn module_name(file_name: &str) -> String {
+    let name = file_name.strip_suffix(".html").unwrap_or(file_name).replace('-', "_");
+    if !is_identifier(&name) {
+        panic!("{}: the name can't be used as a module name", file_name);
+    }
+    name
+}
+
+#[derive(Default)]
+struct ModuleTree {
+    modules: BTreeMap<String, ModuleTree>,
+    templates: BTreeMap<String, String>,
+}
+
+impl ModuleTree {
+    fn write(&self, code: &mut String) {
+        for (name, module) in &self.modules {
+            // Layouts are only rendered through the pages extending them
+            if name == "layouts" {
+                code.push_str("\n#[allow(dead_code)]");
+            }
+            writeln!(code, "\npub mod {} {{", name).unwrap();
+            module.write(code);
+            code.push_str("}\n");
+        }
+        for (name, template) in &self.templates {
+            writeln!(code, "\npub mod {} {{", name).unwrap();
+            code.push_str(template);
+            code.push_str("}\n");
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    Text(String),
+    Value { path: Name, escape: &'static str },
+    If { path: Name, negate: bool, body: Vec<Node> },
+    For { item: String, list: Name, body: Vec<Node> },
+    Block { name: String, body: Vec<Node> },
+}
+
+impl Node {
+    fn is_blank(&self) -> bool {
+        matches!(self, Node::Text(text) if text.trim().is_empty())
+    }
+}
+
+// A name as written in the template, either `field` of the context or `item.field` of a loop element
+#[derive(Clone)]
+struct Name {
+    owner: Option<String>,
+    field: String,
+}
+
+impl Name {
+    fn expression(&self) -> String {
+        match &self.owner {
+            Some(item) => format!("{}.{}", item, self.field),
+            None => format!("ctx.{}", self.field),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum FieldType {
+    Bool,
+    Bytes,
+    List(String),
+}
+
+// The fields of a context struct, in the order the template first uses them
+#[derive(Default)]
+struct Fields {
+    fields: Vec<(String, FieldType)>,
+}
+
+struct Parser<'a> {
+    templates: &'a BTreeMap<String, (String, String)>,
+    // The template being parsed, switched while another one is included
+    file: &'a str,
+    source: &'a str,
+    position: usize,
+    // Names of the templates being included, innermost last
+    including: Vec<&'a str>,
+    // Blocks of the templates extending a layout, the page's own winning over its layout's
+    blocks: BTreeMap<String, Vec<Node>>,
+    // Loop items in scope, innermost last, each with the fields used on it so far
+    scopes: Vec<(String, Fields)>,
+    root: Fields,
+    // Element structs of every list, by struct name
+    items: BTreeMap<String, Fields>,
+}
+
+impl<'a> Parser<'a> {
+    fn fail(&self, at: usize, message: &str) -> ! {
+        let line = self.source[..at].matches('\n').count() + 1;
+        panic!("src/templates/{}:{}: {}", self.file, line, message);
+    }
+
+    // Parses until the closing tag of the enclosing block, returning the nodes and the closing tag
+    fn parse_nodes(&mut self) -> (Vec<Node>, Option<(usize, String)>) {
+        let mut nodes = Vec::new();
+
+        loop {
+            let rest = &self.source[self.position..];
+            let start = match rest.find("{{") {
+                Some(start) => start,
+                None => {
+                    if !rest.is_empty() {
+                        nodes.push(Node::Text(rest.to_string()));
+                    }
+                    self.position = self.source.len();
+                    return (nodes, None);
+                }
+            };
+
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+
+            let tag_start = self.position + start;
+            let raw = self.source[tag_start..].starts_with("{{{");
+            let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+            let tag_end = match self.source[tag_start + open.len()..].find(close) {
+                Some(end) => tag_start + open.len() + end,
+                None => self.fail(tag_start, "unclosed tag"),
+            };
+            let tag = self.source[tag_start + open.len()..tag_end].trim().to_string();
+            self.position = tag_end + close.len();
+
+            if raw {
+                let path = self.resolve(tag_start, &tag, FieldType::Bytes);
+                nodes.push(Node::Value { path, escape: "Raw" });
+            } else if tag.starts_with('/') {
+                return (nodes, Some((tag_start, tag)));
+            } else if let Some(name) = tag.strip_prefix('>') {
+                nodes.extend(self.include(tag_start, name.trim()));
+            } else if let Some(layout) = tag.strip_prefix("#extends ") {
+                if !self.source[..tag_start].trim().is_empty() {
+                    self.fail(tag_start, "`{{#extends}}` has to come first");
+                }
+
+                let (rest, end) = self.parse_nodes();
+                if let Some((at, tag)) = end {
+                    self.fail(at, &format!("`{{{{{}}}}}` closes nothing", tag));
+                }
+                for node in rest {
+                    match node {
+                        Node::Block { name, body } => {
+                            self.blocks.entry(name).or_insert(body);
+                        }
+                        node if node.is_blank() => {}
+                        _ => self.fail(tag_start, "only blocks can follow `{{#extends}}`"),
+                    }
+                }
+                return (self.include(tag_start, layout.trim()), None);
+            } else if let Some(name) = tag.strip_prefix("#block ") {
+                let name = name.trim();
+                let body = self.parse_block(tag_start, "block", name);
+                nodes.push(Node::Block { name: name.to_string(), body });
+            } else if let Some(name) = tag.strip_prefix("#if ") {
+                let name = name.trim();
+                let path = self.resolve(tag_start, name, FieldType::Bool);
+                let body = self.parse_block(tag_start, "if", name);
+                nodes.push(Node::If { path, negate: false, body });
+            } else if let Some(name) = tag.strip_prefix("#else ") {
+                let name = name.trim();
+                let path = self.resolve(tag_start, name, FieldType::Bool);
+                let body = self.parse_block(tag_start, "else", name);
+                nodes.push(Node::If { path, negate: true, body });
+            } else if let Some(header) = tag.strip_prefix("#for ") {
+                let header = header.trim();
+                let (item, list) = match header.split_once(" in ") {
+                    Some((item, list)) => (item.trim().to_string(), list.trim().to_string()),
+                    None => self.fail(tag_start, "expected {{#for item in list}}"),
+                };
+                if !is_identifier(&item) {
+                    self.fail(tag_start, &format!("`{}` isn't a valid loop variable", item));
+                }
+                if self.scopes.iter().any(|(name, _)| *name == item) {
+                    self.fail(tag_start, &format!("`{}` is already a loop variable", item));
+                }
+
+                let struct_name = format!("{}Item", camel_case(list.rsplit('.').next().unwrap()));
+                let list_path = self.resolve(tag_start, &list, FieldType::List(struct_name.clone()));
+
+                // Lists with the same name share their element struct
+                let fields = self.items.remove(&struct_name).unwrap_or_default();
+                self.scopes.push((item.clone(), fields));
+                let body = self.parse_block(tag_start, "for", header);
+                let (_, fields) = self.scopes.pop().unwrap();
+                self.items.insert(struct_name, fields);
+
+                nodes.push(Node::For { item, list: list_path, body });
+            } else if tag.starts_with('#') {
+                self.fail(tag_start, &format!("unknown block `{}`", tag));
+            } else {
+                let escape = escape_context(&self.source[..tag_start]);
+                let path = self.resolve(tag_start, &tag, FieldType::Bytes);
+                nodes.push(Node::Value { path, escape });
+            }
+        }
+    }
+
+    fn parse_block(&mut self, start: usize, kind: &str, name: &str) -> Vec<Node> {
+        let (body, end) = self.parse_nodes();
+
+        match end {
+            Some((at, tag)) => {
+                let closes = tag.strip_prefix('/').and_then(|tag| tag.strip_prefix(kind));
+                match closes.map(str::trim) {
+                    Some(closed) if closed.is_empty() || closed == name => body,
+                    _ => self.fail(at, &format!("`{{{{{}}}}}` doesn't close `{{{{#{} {}}}}}`", tag, kind, name)),
+                }
+            }
+            None => self.fail(start, &format!("`{{{{#{} {}}}}}` is never closed", kind, name)),
+        }
+    }
+
+    // Parses another template in place of the tag at `at`
+    fn include(&mut self, at: usize, name: &str) -> Vec<Node> {
+        let templates = self.templates;
+        let (name, (file, source)) = match templates.get_key_value(name) {
+            Some(template) => template,
+            None => self.fail(at, &format!("there is no template `{}`", name)),
+        };
+        if self.including.contains(&name.as_str()) {
+            self.fail(at, &format!("`{}` ends up including itself", name));
+        }
+
+        let outer = (self.file, self.source, self.position);
+        (self.file, self.source, self.position) = (file.as_str(), source.as_str(), 0);
+        self.including.push(name.as_str());
+
+        let (nodes, end) = self.parse_nodes();
+        if let Some((at, tag)) = end {
+            self.fail(at, &format!("`{{{{{}}}}}` closes nothing", tag));
+        }
+
+        self.including.pop();
+        (self.file, self.source, self.position) = outer;
+        nodes
+    }
+
+    // Records the type a name is used as, on the context or on the loop item it starts with
+    fn resolve(&mut self, at: usize, name: &str, field_type: FieldType) -> Name {
+        let (owner, field) = match name.split_once('.') {
+            Some((owner, field)) => (Some(owner.to_string()), field.to_string()),
+            None => (None, name.to_string()),
+        };
+
+        if !is_identifier(&field) {
+            self.fail(at, &format!("`{}` isn't a valid name", name));
+        }
+
+        let fields = match &owner {
+            Some(owner) => match self.scopes.iter_mut().rev().find(|(item, _)| item == owner) {
+                Some((_, fields)) => fields,
+                None => self.fail(at, &format!("`{}` isn't a loop variable", owner)),
+            },
+            None => &mut self.root,
+        };
+
+        let conflict = match fields.fields.iter().find(|(existing, _)| *existing == field) {
+            Some((_, existing)) if *existing != field_type => Some(describe(existing)),
+            Some(_) => None,
+            None => {
+                fields.fields.push((field.clone(), field_type.clone()));
+                None
+            }
+        };
+
+        if let Some(existing) = conflict {
+            self.fail(at, &format!("`{}` is used both as {} and as {}", name, existing, describe(&field_type)));
+        }
+
+        Name { owner, field }
+    }
+}
+
+fn compile_template(name: &str, templates: &BTreeMap<String, (String, String)>) -> String {
+    let mut parser = Parser {
+        templates,
+        file: "",
+        source: "",
+        position: 0,
+        including: Vec::new(),
+        blocks: BTreeMap::new(),
+        scopes: Vec::new(),
+        root: Fields::default(),
+        items: BTreeMap::new(),
+    };
+
+    let nodes = parser.include(0, name);
+    let mut used = BTreeSet::new();
+    let nodes = fill_blocks(nodes, &parser.blocks, &mut used);
+    if let Some(block) = parser.blocks.keys().find(|block| !used.contains(*block)) {
+        panic!("src/templates/{}: the layout has no block `{}`", templates[name].0, block);
+    }
+
+    let mut code = String::new();
+    code.push_str("    #[allow(unused_imports)]\n    use crate::template::{write_escaped_async, Escape};\n");
+
+    write_struct(&mut code, "Context", &parser.root, &parser.items);
+    for (name, fields) in &parser.items {
+        write_struct(&mut code, name, fields, &parser.items);
+    }
+
+    code.push_str("\n    #[allow(unused_variables)]\n");
+    // Async functions can't leave a struct's lifetime out
+    let context = if borrows(&parser.root) { "Context<'_>" } else { "Context" };
+    writeln!(code, "    pub async fn render_async<W: embedded_io_async::Write>(ctx: &{}, out: &mut W) -> Result<(), W::Error> {{", context).unwrap();
+    write_nodes(&mut code, &nodes, 2);
+    code.push_str("        Ok(())\n    }\n");
+    code
+}
+
+// Swaps the layout's blocks for the ones the page defines, keeping the rest as they are
+fn fill_blocks(nodes: Vec<Node>, blocks: &BTreeMap<String, Vec<Node>>, used: &mut BTreeSet<String>) -> Vec<Node> {
+    let mut filled = Vec::new();
+
+    for node in nodes {
+        match node {
+            Node::Block { name, body } => {
+                let body = match blocks.get(&name) {
+                    Some(replacement) => replacement.clone(),
+                    None => body,
+                };
+                used.insert(name);
+                filled.extend(fill_blocks(body, blocks, used));
+            }
+            Node::If { path, negate, body } => filled.push(Node::If { path, negate, body: fill_blocks(body, blocks, used) }),
+            Node::For { item, list, body } => filled.push(Node::For { item, list, body: fill_blocks(body, blocks, used) }),
+            node => filled.push(node),
+        }
+    }
+    filled
+}
+
+fn write_struct(code: &mut String, name: &str, fields: &Fields, items: &BTreeMap<String, Fields>) {
+    let lifetime = if borrows(fields) { "<'a>" } else { "" };
+
+    writeln!(code, "\n    pub struct {}{} {{", name, lifetime).unwrap();
+    for (field, field_type) in &fields.fields {
+        let rust_type = match field_type {
+            FieldType::Bool => "bool".to_string(),
+            FieldType::Bytes => "&'a [u8]".to_string(),
+            FieldType::List(item) => {
+                let item_lifetime = if borrows(&items[item]) { "<'a>" } else { "" };
+                format!("&'a [{}{}]", item, item_lifetime)
+            }
+        };
+        writeln!(code, "        pub {}: {},", field, rust_type).unwrap();
+    }
+    code.push_str("    }\n");
+}
+
+fn borrows(fields: &Fields) -> bool {
+    fields.fields.iter().any(|(_, field_type)| *field_type != FieldType::Bool)
+}
+
+fn write_nodes(code: &mut String, nodes: &[Node], depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    for node in nodes {
+        match node {
+            Node::Text(text) => writeln!(code, "{}out.write_all({:?}.as_bytes()).await?;", indent, text).unwrap(),
+            Node::Value { path, escape } => {
+                writeln!(code, "{}write_escaped_async(out, {}, Escape::{}).await?;", indent, path.expression(), escape).unwrap()
+            }
+            Node::If { path, negate, body } => {
+                writeln!(code, "{}if {}{} {{", indent, if *negate { "!" } else { "" }, path.expression()).unwrap();
+                write_nodes(code, body, depth + 1);
+                writeln!(code, "{}}}", indent).unwrap();
+            }
+            Node::For { item, list, body } => {
+                writeln!(code, "{}for {} in {}.iter() {{", indent, item, list.expression()).unwrap();
+                write_nodes(code, body, depth + 1);
+                writeln!(code, "{}}}", indent).unwrap();
+            }
+            // Already filled in by fill_blocks
+            Node::Block { body, .. } => write_nodes(code, body, depth),
+        }
+    }
+}
+
+// Mirrors the runtime Renderer: inside a tag when the nearest angle bracket before is a `<`
+fn escape_context(before: &str) -> &'static str {
+    match before.rfind(|c| c == '<' || c == '>') {
+        Some(position) if before.as_bytes()[position] == b'<' => "Attribute",
+        _ => "Html",
+    }
+}
+
+fn describe(field_type: &FieldType) -> &'static str {
+    match field_type {
+        FieldType::Bool => "a condition",
+        FieldType::Bytes => "a value",
+        FieldType::List(_) => "a list",
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn is_identifier(name: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in",
+        "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "static", "struct", "super",
+        "trait", "true", "type", "unsafe", "use", "where", "while", "async", "await", "dyn", "ctx", "out",
+    ];
+
+    let mut chars = name.chars();
+    chars.next().map_or(false, |first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
@@ -0,0 +1,547 @@
+use crate::http::ByteString;
+
+pub const MAX_CLAIM_LENGTH: usize = 64;
+pub const MAX_CLAIM_NAME_LENGTH: usize = 32;
+pub const MAX_CUSTOM_CLAIMS: usize = 4;
+
+// Registered claims that have their own field, they can't be set as custom claims
+const RESERVED_CLAIMS: [&[u8]; 7] = [b"sub", b"iss", b"aud", b"iat", b"nbf", b"exp", b"role"];
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ClaimValue {
+    Number(i64),
+    String(ByteString<MAX_CLAIM_LENGTH>),
+}
+
+// The payload of a token, e.g.
+// Claims::new().subject(b"7").role(1).issued_at(now).expires_at(now + 3600)
+// Strings longer than MAX_CLAIM_LENGTH are cut short by the builder methods.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Claims {
+    pub sub: Option<ByteString<MAX_CLAIM_LENGTH>>,
+    pub iss: Option<ByteString<MAX_CLAIM_LENGTH>>,
+    pub aud: Option<ByteString<MAX_CLAIM_LENGTH>>,
+    pub iat: Option<u64>,
+    pub nbf: Option<u64>,
+    pub exp: Option<u64>,
+    pub role: Option<u8>,
+    custom: [Option<(ByteString<MAX_CLAIM_NAME_LENGTH>, ClaimValue)>; MAX_CUSTOM_CLAIMS],
+}
+
+impl Claims {
+    pub fn new() -> Claims {
+        Claims {
+            sub: None,
+            iss: None,
+            aud: None,
+            iat: None,
+            nbf: None,
+            exp: None,
+            role: None,
+            custom: [None; MAX_CUSTOM_CLAIMS],
+        }
+    }
+
+    pub fn subject(mut self, sub: &[u8]) -> Claims {
+        self.sub = Some(ByteString::new(sub));
+        self
+    }
+
+    pub fn issuer(mut self, iss: &[u8]) -> Claims {
+        self.iss = Some(ByteString::new(iss));
+        self
+    }
+
+    pub fn audience(mut self, aud: &[u8]) -> Claims {
+        self.aud = Some(ByteString::new(aud));
+        self
+    }
+
+    pub fn issued_at(mut self, timestamp: u64) -> Claims {
+        self.iat = Some(timestamp);
+        self
+    }
+
+    pub fn not_before(mut self, timestamp: u64) -> Claims {
+        self.nbf = Some(timestamp);
+        self
+    }
+
+    pub fn expires_at(mut self, timestamp: u64) -> Claims {
+        self.exp = Some(timestamp);
+        self
+    }
+
+    pub fn role(mut self, role: u8) -> Claims {
+        self.role = Some(role);
+        self
+    }
+
+    pub fn set_string(&mut self, name: &[u8], value: &[u8]) -> Result<(), &'static str> {
+        if value.len() > MAX_CLAIM_LENGTH {
+            return Err("Claim value too long");
+        }
+        self.set_custom(name, ClaimValue::String(ByteString::new(value)))
+    }
+
+    pub fn set_number(&mut self, name: &[u8], value: i64) -> Result<(), &'static str> {
+        self.set_custom(name, ClaimValue::Number(value))
+    }
+
+    pub fn custom(&self, name: &[u8]) -> Option<&ClaimValue> {
+        self.custom.iter().flatten()
+            .find(|(claim_name, _)| claim_name.as_bytes() == name)
+            .map(|(_, value)| value)
+    }
+
+    // Replaces a custom claim of the same name, or takes a free slot
+    fn set_custom(&mut self, name: &[u8], value: ClaimValue) -> Result<(), &'static str> {
+        if name.is_empty() || name.len() > MAX_CLAIM_NAME_LENGTH {
+            return Err("Invalid claim name");
+        }
+        if RESERVED_CLAIMS.contains(&name) {
+            return Err("Reserved claim name");
+        }
+
+        if let Some(existing) = self.custom.iter_mut().flatten().find(|(claim_name, _)| claim_name.as_bytes() == name) {
+            existing.1 = value;
+            return Ok(());
+        }
+
+        match self.custom.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some((ByteString::new(name), value));
+                Ok(())
+            }
+            None => Err("Too many claims"),
+        }
+    }
+
+    // Compact JSON, only the claims that are set
+    pub fn write_json(&self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let mut writer = JsonWriter { buffer, length: 0, first: true };
+        writer.push(b"{")?;
+
+        if let Some(sub) = &self.sub {
+            writer.string_member(b"sub", sub.as_bytes())?;
+        }
+        if let Some(iss) = &self.iss {
+            writer.string_member(b"iss", iss.as_bytes())?;
+        }
+        if let Some(aud) = &self.aud {
+            writer.string_member(b"aud", aud.as_bytes())?;
+        }
+        if let Some(iat) = self.iat {
+            writer.number_member(b"iat", false, iat)?;
+        }
+        if let Some(nbf) = self.nbf {
+            writer.number_member(b"nbf", false, nbf)?;
+        }
+        if let Some(exp) = self.exp {
+            writer.number_member(b"exp", false, exp)?;
+        }
+        if let Some(role) = self.role {
+            writer.number_member(b"role", false, role as u64)?;
+        }
+
+        for (name, value) in self.custom.iter().flatten() {
+            match value {
+                ClaimValue::Number(number) => writer.number_member(name.as_bytes(), *number < 0, number.unsigned_abs())?,
+                ClaimValue::String(string) => writer.string_member(name.as_bytes(), string.as_bytes())?,
+            }
+        }
+
+        writer.push(b"}")?;
+        Ok(writer.length)
+    }
+
+    // Reads a flat JSON object of string and integer members, booleans and nulls are skipped
+    pub fn from_json(json: &[u8]) -> Result<Claims, &'static str> {
+        let mut claims = Claims::new();
+
+        read_object(json, |name, value| {
+            match value {
+                JsonValue::Skipped => {}
+                JsonValue::String(value) => match name {
+                    b"sub" => claims.sub = Some(value),
+                    b"iss" => claims.iss = Some(value),
+                    b"aud" => claims.aud = Some(value),
+                    b"iat" | b"nbf" | b"exp" | b"role" => return Err("Invalid claim type"),
+                    name => claims.set_custom(name, ClaimValue::String(value))?,
+                },
+                JsonValue::Number(value) => match name {
+                    b"iat" => claims.iat = Some(timestamp(value)?),
+                    b"nbf" => claims.nbf = Some(timestamp(value)?),
+                    b"exp" => claims.exp = Some(timestamp(value)?),
+                    b"role" => claims.role = Some(u8::try_from(value).map_err(|_| "Invalid claim type")?),
+                    b"sub" | b"iss" | b"aud" => return Err("Invalid claim type"),
+                    name => claims.set_custom(name, ClaimValue::Number(value))?,
+                },
+            }
+            Ok(())
+        })?;
+
+        Ok(claims)
+    }
+}
+
+// The JOSE header in front of the claims, only the members verification cares about
+#[derive(Copy, Clone, PartialEq)]
+pub struct Header {
+    pub alg: ByteString<16>,
+    pub kid: Option<ByteString<MAX_CLAIM_LENGTH>>,
+}
+
+impl Header {
+    pub fn from_json(json: &[u8]) -> Result<Header, &'static str> {
+        let mut alg = None;
+        let mut kid = None;
+
+        read_object(json, |name, value| {
+            match (name, value) {
+                (b"alg", JsonValue::String(value)) if value.as_bytes().len() <= 16 => alg = Some(ByteString::new(value.as_bytes())),
+                (b"kid", JsonValue::String(value)) => kid = Some(value),
+                (b"alg" | b"kid", _) => return Err("Invalid header"),
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        Ok(Header { alg: alg.ok_or("Invalid header")?, kid })
+    }
+}
+
+// Calls back with every member of a flat JSON object
+fn read_object<F>(json: &[u8], mut member: F) -> Result<(), &'static str>
+    where
+        F: FnMut(&[u8], JsonValue) -> Result<(), &'static str>,
+{
+    let mut reader = JsonReader { input: json, position: 0 };
+
+    reader.expect(b'{')?;
+    if reader.peek() == Some(b'}') {
+        reader.position += 1;
+        return reader.end();
+    }
+
+    loop {
+        let name = reader.string::<MAX_CLAIM_NAME_LENGTH>()?;
+        reader.expect(b':')?;
+        member(name.as_bytes(), reader.value()?)?;
+
+        match reader.next_byte()? {
+            b',' => continue,
+            b'}' => break,
+            _ => return Err("Malformed JSON"),
+        }
+    }
+
+    reader.end()
+}
+
+fn timestamp(value: i64) -> Result<u64, &'static str> {
+    u64::try_from(value).map_err(|_| "Invalid claim type")
+}
+
+struct JsonWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    first: bool,
+}
+
+impl<'a> JsonWriter<'a> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let end = self.length + bytes.len();
+        if end > self.buffer.len() {
+            return Err("Claims don't fit the buffer");
+        }
+        self.buffer[self.length..end].copy_from_slice(bytes);
+        self.length = end;
+        Ok(())
+    }
+
+    fn name(&mut self, name: &[u8]) -> Result<(), &'static str> {
+        if !self.first {
+            self.push(b",")?;
+        }
+        self.first = false;
+        self.string(name)?;
+        self.push(b":")
+    }
+
+    fn string(&mut self, value: &[u8]) -> Result<(), &'static str> {
+        const HEX: &[u8] = b"0123456789abcdef";
+
+        self.push(b"\"")?;
+        for &byte in value {
+            match byte {
+                b'"' => self.push(b"\\\"")?,
+                b'\\' => self.push(b"\\\\")?,
+                0..=0x1f => self.push(&[b'\\', b'u', b'0', b'0', HEX[(byte >> 4) as usize], HEX[(byte & 0xf) as usize]])?,
+                _ => self.push(&[byte])?,
+            }
+        }
+        self.push(b"\"")
+    }
+
+    fn string_member(&mut self, name: &[u8], value: &[u8]) -> Result<(), &'static str> {
+        self.name(name)?;
+        self.string(value)
+    }
+
+    fn number_member(&mut self, name: &[u8], negative: bool, mut value: u64) -> Result<(), &'static str> {
+        self.name(name)?;
+        if negative {
+            self.push(b"-")?;
+        }
+
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(&digits[start..])
+    }
+}
+
+enum JsonValue {
+    String(ByteString<MAX_CLAIM_LENGTH>),
+    Number(i64),
+    Skipped,
+}
+
+struct JsonReader<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> JsonReader<'a> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.input.get(self.position), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.input.get(self.position).copied()
+    }
+
+    fn next_byte(&mut self) -> Result<u8, &'static str> {
+        let byte = self.peek().ok_or("Malformed JSON")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), &'static str> {
+        if self.next_byte()? == expected {
+            Ok(())
+        } else {
+            Err("Malformed JSON")
+        }
+    }
+
+    fn end(&mut self) -> Result<(), &'static str> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err("Malformed JSON"),
+        }
+    }
+
+    fn value(&mut self) -> Result<JsonValue, &'static str> {
+        match self.peek().ok_or("Malformed JSON")? {
+            b'"' => Ok(JsonValue::String(self.string()?)),
+            b'-' | b'0'..=b'9' => Ok(JsonValue::Number(self.number()?)),
+            _ => {
+                for literal in [&b"true"[..], b"false", b"null"] {
+                    if self.input[self.position..].starts_with(literal) {
+                        self.position += literal.len();
+                        return Ok(JsonValue::Skipped);
+                    }
+                }
+                Err("Unsupported claim value")
+            }
+        }
+    }
+
+    fn string<const N: usize>(&mut self) -> Result<ByteString<N>, &'static str> {
+        self.expect(b'"')?;
+        let input = self.input;
+        let mut output = ByteString::<N>::new(b"");
+        let mut length = 0;
+
+        loop {
+            let byte = *self.input.get(self.position).ok_or("Malformed JSON")?;
+            self.position += 1;
+
+            let mut utf8 = [0u8; 4];
+            let decoded: &[u8] = match byte {
+                b'"' => return Ok(output),
+                b'\\' => {
+                    let escape = *self.input.get(self.position).ok_or("Malformed JSON")?;
+                    self.position += 1;
+                    match escape {
+                        b'"' | b'\\' | b'/' => core::slice::from_ref(&input[self.position - 1]),
+                        b'b' => b"\x08",
+                        b'f' => b"\x0c",
+                        b'n' => b"\n",
+                        b'r' => b"\r",
+                        b't' => b"\t",
+                        b'u' => {
+                            let character = self.unicode_escape()?;
+                            character.encode_utf8(&mut utf8).as_bytes()
+                        }
+                        _ => return Err("Malformed JSON"),
+                    }
+                }
+                0..=0x1f => return Err("Malformed JSON"),
+                _ => core::slice::from_ref(&input[self.position - 1]),
+            };
+
+            length += decoded.len();
+            if length > N {
+                return Err("Claim value too long");
+            }
+            output.append(decoded);
+        }
+    }
+
+    // The XXXX of a \uXXXX escape, joining surrogate pairs
+    fn unicode_escape(&mut self) -> Result<char, &'static str> {
+        let high = self.hex4()?;
+
+        let code_point = if (0xd800..0xdc00).contains(&high) {
+            if !self.input[self.position..].starts_with(b"\\u") {
+                return Err("Malformed JSON");
+            }
+            self.position += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err("Malformed JSON");
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+
+        char::from_u32(code_point).ok_or("Malformed JSON")
+    }
+
+    fn hex4(&mut self) -> Result<u32, &'static str> {
+        let digits = self.input.get(self.position..self.position + 4).ok_or("Malformed JSON")?;
+        self.position += 4;
+
+        digits.iter().try_fold(0u32, |value, &digit| {
+            let nibble = (digit as char).to_digit(16).ok_or("Malformed JSON")?;
+            Ok(value << 4 | nibble)
+        })
+    }
+
+    // Integers only, claims have no use for fractions
+    fn number(&mut self) -> Result<i64, &'static str> {
+        let negative = self.input[self.position] == b'-';
+        if negative {
+            self.position += 1;
+        }
+
+        let start = self.position;
+        let mut value: i64 = 0;
+        while let Some(&digit @ b'0'..=b'9') = self.input.get(self.position) {
+            value = value.checked_mul(10)
+                .and_then(|value| value.checked_add((digit - b'0') as i64))
+                .ok_or("Number out of range")?;
+            self.position += 1;
+        }
+
+        if self.position == start || matches!(self.input.get(self.position), Some(b'.' | b'e' | b'E')) {
+            return Err("Unsupported claim value");
+        }
+
+        Ok(if negative { -value } else { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claims_json() {
+        let mut claims = Claims::new()
+            .subject(b"7")
+            .issuer(b"pico")
+            .audience(b"web")
+            .issued_at(100)
+            .expires_at(3700)
+            .role(1);
+        claims.set_string(b"name", b"Ann \"the\" admin").unwrap();
+        claims.set_number(b"level", -3).unwrap();
+
+        let mut buffer = [0u8; 256];
+        let length = claims.write_json(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..length],
+            &br#"{"sub":"7","iss":"pico","aud":"web","iat":100,"exp":3700,"role":1,"name":"Ann \"the\" admin","level":-3}"#[..]
+        );
+
+        let decoded = Claims::from_json(&buffer[..length]).unwrap();
+        assert!(decoded == claims);
+        assert!(decoded.custom(b"level") == Some(&ClaimValue::Number(-3)));
+        assert!(decoded.nbf.is_none());
+
+        // Too small a buffer is an error, not a cut off token
+        assert!(claims.write_json(&mut buffer[..32]).is_err());
+    }
+
+    #[test]
+    fn test_claims_custom() {
+        let mut claims = Claims::new();
+        assert_eq!(claims.set_number(b"exp", 1), Err("Reserved claim name"));
+        assert_eq!(claims.set_string(b"name", &[b'a'; MAX_CLAIM_LENGTH + 1]), Err("Claim value too long"));
+
+        for index in 0..MAX_CUSTOM_CLAIMS {
+            claims.set_number(&[b'a' + index as u8], index as i64).unwrap();
+        }
+        assert_eq!(claims.set_number(b"z", 0), Err("Too many claims"));
+
+        // Setting an existing name replaces it
+        claims.set_number(b"a", 42).unwrap();
+        assert!(claims.custom(b"a") == Some(&ClaimValue::Number(42)));
+    }
+
+    #[test]
+    fn test_claims_from_json() {
+        let claims = Claims::from_json(b" { \"sub\" : \"caf\\u00e9 \\ud83d\\ude00\\n\", \"admin\": true, \"exp\": 5, \"x\": null } ").unwrap();
+        assert_eq!(claims.sub.unwrap().as_bytes(), "caf\u{e9} \u{1f600}\n".as_bytes());
+        assert_eq!(claims.exp, Some(5));
+        assert!(claims.custom(b"admin").is_none());
+
+        assert!(Claims::from_json(b"{}").unwrap() == Claims::new());
+
+        assert_eq!(Claims::from_json(b"{\"exp\":\"soon\"}").err(), Some("Invalid claim type"));
+        assert_eq!(Claims::from_json(b"{\"exp\":-1}").err(), Some("Invalid claim type"));
+        assert_eq!(Claims::from_json(b"{\"role\":256}").err(), Some("Invalid claim type"));
+        assert_eq!(Claims::from_json(b"{\"exp\":1.5}").err(), Some("Unsupported claim value"));
+        assert_eq!(Claims::from_json(b"{\"aud\":[\"a\"]}").err(), Some("Unsupported claim value"));
+        assert_eq!(Claims::from_json(b"{\"exp\":99999999999999999999}").err(), Some("Number out of range"));
+        assert_eq!(Claims::from_json(b"{\"sub\":\"1\"").err(), Some("Malformed JSON"));
+        assert_eq!(Claims::from_json(b"{\"sub\":\"1\"} x").err(), Some("Malformed JSON"));
+        assert_eq!(Claims::from_json(b"{\"sub\":\"\\x\"}").err(), Some("Malformed JSON"));
+    }
+
+    #[test]
+    fn test_header_from_json() {
+        let header = Header::from_json(br#"{"typ":"JWT","alg":"ES256","kid":"k1","x5t":null}"#).unwrap();
+        assert_eq!(header.alg.as_bytes(), b"ES256");
+        assert_eq!(header.kid.unwrap().as_bytes(), b"k1");
+
+        assert!(Header::from_json(br#"{"typ":"JWT"}"#).is_err());
+        assert!(Header::from_json(br#"{"alg":1}"#).is_err());
+        assert!(Header::from_json(br#"{"alg":"ES256","kid":2}"#).is_err());
+    }
+}
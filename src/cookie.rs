@@ -0,0 +1,257 @@
+use crate::http::{trim_bytes, usize_to_bytes, ByteString};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+// A Set-Cookie line under construction, e.g.
+// Cookie::new(b"session", id).path(b"/").max_age(3600).http_only().same_site(SameSite::Lax)
+// Names and values are written as given, so they must already be cookie safe (base64url is).
+#[derive(Copy, Clone)]
+pub struct Cookie<'a> {
+    pub name: &'a [u8],
+    pub value: &'a [u8],
+    pub path: Option<&'a [u8]>,
+    pub domain: Option<&'a [u8]>,
+    pub max_age: Option<u32>,
+    // Unix timestamp, written out as an HTTP date
+    pub expires: Option<u64>,
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl<'a> Cookie<'a> {
+    pub fn new(name: &'a [u8], value: &'a [u8]) -> Cookie<'a> {
+        Cookie {
+            name,
+            value,
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    // Tells the browser to drop a cookie it already has, the path has to match the original
+    pub fn removal(name: &'a [u8]) -> Cookie<'a> {
+        Cookie::new(name, b"").max_age(0).expires(0)
+    }
+
+    pub fn path(mut self, path: &'a [u8]) -> Cookie<'a> {
+        self.path = Some(path);
+        self
+    }
+
+    pub fn domain(mut self, domain: &'a [u8]) -> Cookie<'a> {
+        self.domain = Some(domain);
+        self
+    }
+
+    pub fn max_age(mut self, seconds: u32) -> Cookie<'a> {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    pub fn expires(mut self, timestamp: u64) -> Cookie<'a> {
+        self.expires = Some(timestamp);
+        self
+    }
+
+    pub fn http_only(mut self) -> Cookie<'a> {
+        self.http_only = true;
+        self
+    }
+
+    pub fn secure(mut self) -> Cookie<'a> {
+        self.secure = true;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie<'a> {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    // Writes the value of the Set-Cookie header
+    pub fn write_to<const N: usize>(&self, output: &mut ByteString<N>) {
+        output.append(self.name);
+        output.append(b"=");
+        output.append(self.value);
+
+        if let Some(path) = self.path {
+            output.append(b"; Path=");
+            output.append(path);
+        }
+
+        if let Some(domain) = self.domain {
+            output.append(b"; Domain=");
+            output.append(domain);
+        }
+
+        if let Some(max_age) = self.max_age {
+            let mut digits = [0u8; 10];
+            let length = usize_to_bytes(max_age as usize, &mut digits);
+            output.append(b"; Max-Age=");
+            output.append(&digits[..length]);
+        }
+
+        if let Some(expires) = self.expires {
+            let mut date = [0u8; 29];
+            format_http_date(expires, &mut date);
+            output.append(b"; Expires=");
+            output.append(&date);
+        }
+
+        if self.http_only {
+            output.append(b"; HttpOnly");
+        }
+
+        // Browsers refuse SameSite=None unless the cookie is also Secure
+        if self.secure || self.same_site == Some(SameSite::None) {
+            output.append(b"; Secure");
+        }
+
+        match self.same_site {
+            Some(SameSite::Strict) => output.append(b"; SameSite=Strict"),
+            Some(SameSite::Lax) => output.append(b"; SameSite=Lax"),
+            Some(SameSite::None) => output.append(b"; SameSite=None"),
+            None => {}
+        }
+    }
+}
+
+// Walks the name=value pairs of a Cookie request header
+pub struct Cookies<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Cookies<'a> {
+    pub fn new(header_value: &'a [u8]) -> Cookies<'a> {
+        Cookies { remaining: header_value }
+    }
+}
+
+impl<'a> Iterator for Cookies<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.remaining.is_empty() {
+            let (pair, rest) = match self.remaining.iter().position(|&b| b == b';') {
+                Some(index) => (&self.remaining[..index], &self.remaining[index + 1..]),
+                None => (self.remaining, &b""[..]),
+            };
+            self.remaining = rest;
+
+            // Pairs without an equals sign are skipped, like browsers do
+            if let Some(equals_index) = pair.iter().position(|&b| b == b'=') {
+                let name = trim_bytes(&pair[..equals_index]);
+                let mut value = trim_bytes(&pair[equals_index + 1..]);
+
+                if value.len() >= 2 && value[0] == b'"' && value[value.len() - 1] == b'"' {
+                    value = &value[1..value.len() - 1];
+                }
+
+                if !name.is_empty() {
+                    return Some((name, value));
+                }
+            }
+        }
+        None
+    }
+}
+
+// Formats a unix timestamp as an IMF-fixdate, e.g. "Thu, 01 Jan 1970 00:00:00 GMT"
+pub fn format_http_date(timestamp: u64, buffer: &mut [u8; 29]) {
+    const DAYS: [&[u8]; 7] = [b"Thu", b"Fri", b"Sat", b"Sun", b"Mon", b"Tue", b"Wed"];
+    const MONTHS: [&[u8]; 12] = [b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec"];
+
+    let days = timestamp / 86400;
+    let seconds_of_day = timestamp % 86400;
+
+    // Civil date from days since the epoch, see Howard Hinnant's days_from_civil
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    buffer[..3].copy_from_slice(DAYS[(days % 7) as usize]);
+    buffer[3..5].copy_from_slice(b", ");
+    write_two_digits(day as u64, &mut buffer[5..7]);
+    buffer[7] = b' ';
+    buffer[8..11].copy_from_slice(MONTHS[(month - 1) as usize]);
+    buffer[11] = b' ';
+    write_two_digits(year as u64 / 100, &mut buffer[12..14]);
+    write_two_digits(year as u64 % 100, &mut buffer[14..16]);
+    buffer[16] = b' ';
+    write_two_digits(seconds_of_day / 3600, &mut buffer[17..19]);
+    buffer[19] = b':';
+    write_two_digits(seconds_of_day / 60 % 60, &mut buffer[20..22]);
+    buffer[22] = b':';
+    write_two_digits(seconds_of_day % 60, &mut buffer[23..25]);
+    buffer[25..].copy_from_slice(b" GMT");
+}
+
+fn write_two_digits(value: u64, buffer: &mut [u8]) {
+    buffer[0] = b'0' + (value / 10 % 10) as u8;
+    buffer[1] = b'0' + (value % 10) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookies() {
+        let mut cookies = Cookies::new(b"session=abc123; theme=\"dark\";broken; flash=Saved+file");
+
+        assert_eq!(cookies.next(), Some((&b"session"[..], &b"abc123"[..])));
+        assert_eq!(cookies.next(), Some((&b"theme"[..], &b"dark"[..])));
+        assert_eq!(cookies.next(), Some((&b"flash"[..], &b"Saved+file"[..])));
+        assert_eq!(cookies.next(), None);
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let mut value = ByteString::<256>::new(b"");
+        Cookie::new(b"session", b"abc123")
+            .path(b"/")
+            .max_age(3600)
+            .expires(1700000000)
+            .http_only()
+            .secure()
+            .same_site(SameSite::Lax)
+            .write_to(&mut value);
+
+        assert_eq!(
+            value.as_bytes(),
+            &b"session=abc123; Path=/; Max-Age=3600; Expires=Tue, 14 Nov 2023 22:13:20 GMT; HttpOnly; Secure; SameSite=Lax"[..]
+        );
+
+        let mut value = ByteString::<256>::new(b"");
+        Cookie::removal(b"session").path(b"/").write_to(&mut value);
+        assert_eq!(value.as_bytes(), &b"session=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"[..]);
+    }
+
+    #[test]
+    fn test_format_http_date() {
+        let mut date = [0u8; 29];
+
+        format_http_date(951782400, &mut date);
+        assert_eq!(&date, b"Tue, 29 Feb 2000 00:00:00 GMT");
+
+        format_http_date(4102444799, &mut date);
+        assert_eq!(&date, b"Thu, 31 Dec 2099 23:59:59 GMT");
+    }
+}
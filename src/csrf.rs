@@ -0,0 +1,91 @@
+use rand::RngCore;
+
+use crate::base64::base64_url_encode;
+use crate::cookie::{Cookie, SameSite};
+use crate::hmac::constant_time_eq;
+use crate::http::{Request, Response, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+
+pub const CSRF_COOKIE: &[u8] = b"csrf";
+pub const CSRF_FIELD: &[u8] = b"csrf_token";
+// base64url of 16 random bytes
+pub const CSRF_TOKEN_LENGTH: usize = 22;
+
+// Double submit: the same random token goes in a cookie and in a hidden form field. Another
+// site can make the browser send the cookie but can't read it to fill in the field.
+pub fn issue<R: RngCore>(rng: &mut R, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>) -> [u8; CSRF_TOKEN_LENGTH] {
+    let mut random = [0u8; 16];
+    rng.fill_bytes(&mut random);
+
+    let mut token = [0u8; CSRF_TOKEN_LENGTH];
+    base64_url_encode(&random, &mut token);
+
+    resp.set_cookie(&Cookie::new(CSRF_COOKIE, &token)
+        .path(b"/")
+        .http_only()
+        .same_site(SameSite::Strict));
+
+    token
+}
+
+// True when the posted field matches the cookie
+pub fn verify(req: &Request) -> bool {
+    matches(req.cookie(CSRF_COOKIE), req.post(CSRF_FIELD))
+}
+
+// For multipart forms, whose body is streamed and only looked at after the route has been
+// authorized. The token goes in the form's action URL instead.
+pub fn verify_query(req: &Request) -> bool {
+    matches(req.cookie(CSRF_COOKIE), req.get(CSRF_FIELD))
+}
+
+fn matches(cookie: Option<&[u8]>, field: Option<&[u8]>) -> bool {
+    match (cookie, field) {
+        (Some(cookie), Some(field)) => cookie.len() == CSRF_TOKEN_LENGTH && constant_time_eq(cookie, field),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::ByteString;
+    use rand::rngs::mock::StepRng;
+
+    // Both tokens are CSRF_TOKEN_LENGTH long, so the body is always 33 bytes
+    fn login_request(cookie: &[u8], field: &[u8]) -> bool {
+        let mut request = ByteString::<512>::new(b"POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 33\r\nCookie: csrf=");
+        request.append(cookie);
+        request.append(b"\r\n\r\ncsrf_token=");
+        request.append(field);
+
+        let mut req = Request::new();
+        assert_eq!(req.parse(request.as_bytes(), request.as_bytes().len()), Ok(()));
+        verify(&req)
+    }
+
+    #[test]
+    fn test_csrf() {
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        let token = issue(&mut StepRng::new(1, 1), &mut resp);
+
+        let set_cookie = resp.set_cookies().next().unwrap();
+        assert!(set_cookie.starts_with(b"csrf="));
+        assert_eq!(&set_cookie[5..5 + CSRF_TOKEN_LENGTH], &token);
+
+        assert!(login_request(&token, &token));
+
+        let mut other = token;
+        other[0] = if other[0] == b'A' { b'B' } else { b'A' };
+        assert!(!login_request(&token, &other));
+
+        let mut request = ByteString::<512>::new(b"POST /sd-card/upload?csrf_token=");
+        request.append(&token);
+        request.append(b" HTTP/1.1\r\nCookie: csrf=");
+        request.append(&token);
+        request.append(b"\r\n\r\n");
+        let mut req = Request::new();
+        assert_eq!(req.parse(request.as_bytes(), request.as_bytes().len()), Ok(()));
+        assert!(verify_query(&req));
+        assert!(!verify(&req));
+    }
+}
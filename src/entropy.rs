@@ -0,0 +1,110 @@
+use core::cell::RefCell;
+use core::ffi::{c_int, c_uint};
+
+#[cfg(not(test))]
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use micro_ecc_sys::uECC_set_rng;
+use rand::{CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+// Single ring oscillator bits are biased and correlated, so far more are gathered than the
+// seed needs and SHA-256 condenses them
+const ENTROPY_POOL_SIZE: usize = 256;
+
+static RNG: Mutex<CriticalSectionRawMutex, RefCell<Option<ChaCha20Rng>>> = Mutex::new(RefCell::new(None));
+
+// Seeds the generator and hands it to micro-ecc, which has no entropy source of its own on the
+// Pico, so key generation and signing work. Call before anything needs randomness.
+pub fn init() {
+    fill_bytes(&mut [0u8; 4]);
+
+    unsafe {
+        uECC_set_rng(Some(uecc_rng));
+    }
+}
+
+// Random bytes from the shared generator, seeding it first if nobody has yet
+pub fn fill_bytes(dest: &mut [u8]) {
+    RNG.lock(|rng| {
+        rng.borrow_mut()
+            .get_or_insert_with(|| ChaCha20Rng::from_seed(gather_seed()))
+            .fill_bytes(dest)
+    });
+}
+
+fn gather_seed() -> [u8; 32] {
+    let mut pool = [0u8; ENTROPY_POOL_SIZE];
+    gather(&mut pool);
+    Sha256::digest(pool).into()
+}
+
+#[cfg(not(test))]
+fn gather(pool: &mut [u8]) {
+    RoscRng.fill_bytes(pool);
+}
+
+// Host tests have no oscillator, they get the same sequence on every run instead
+#[cfg(test)]
+fn gather(pool: &mut [u8]) {
+    for (index, byte) in pool.iter_mut().enumerate() {
+        *byte = index as u8;
+    }
+}
+
+unsafe extern "C" fn uecc_rng(dest: *mut u8, size: c_uint) -> c_int {
+    fill_bytes(core::slice::from_raw_parts_mut(dest, size as usize));
+    1
+}
+
+// Handle to the shared generator for code that takes an RngCore, e.g. SessionStore::start
+#[derive(Copy, Clone)]
+pub struct Csprng;
+
+impl RngCore for Csprng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Csprng {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csprng() {
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        Csprng.fill_bytes(&mut first);
+        Csprng.fill_bytes(&mut second);
+
+        // The stream moves on, and isn't the raw pool
+        assert_ne!(first, second);
+        assert_ne!(first, [0u8; 32]);
+        assert_ne!(&first[..4], &[0, 1, 2, 3]);
+
+        // The stand-in pool makes the seed the same on every run
+        assert_eq!(gather_seed(), gather_seed());
+    }
+}
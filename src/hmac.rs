@@ -0,0 +1,167 @@
+use embassy_futures::yield_now;
+use sha2::{Digest, Sha256};
+
+const BLOCK_SIZE: usize = 64;
+// PBKDF2 rounds between giving the other tasks a turn
+const ROUNDS_PER_YIELD: u32 = 64;
+
+// HMAC-SHA256 (RFC 2104), fed incrementally like the hash itself. Both padded keys are hashed
+// up front, so a clone carries on from there without compressing them again.
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        // Keys longer than a block are hashed first, shorter ones are zero padded
+        let mut block_key = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            block_key[..32].copy_from_slice(&Sha256::digest(key));
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner_key = [0u8; BLOCK_SIZE];
+        let mut outer_key = [0u8; BLOCK_SIZE];
+        for i in 0..BLOCK_SIZE {
+            inner_key[i] = block_key[i] ^ 0x36;
+            outer_key[i] = block_key[i] ^ 0x5c;
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(inner_key);
+        let mut outer = Sha256::new();
+        outer.update(outer_key);
+
+        HmacSha256 { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        let inner_hash = self.inner.finalize();
+
+        let mut outer = self.outer;
+        outer.update(inner_hash);
+        outer.finalize().into()
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(message);
+    mac.finalize()
+}
+
+// PBKDF2-HMAC-SHA256 (RFC 8018), filling the whole output with derived key bytes. Thousands of
+// rounds take a while on the device, so it yields every ROUNDS_PER_YIELD of them.
+pub async fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) {
+    // The password keys every HMAC, so pad it once and clone the keyed state per round
+    let keyed = HmacSha256::new(password);
+
+    for (block_index, chunk) in output.chunks_mut(32).enumerate() {
+        let mut mac = keyed.clone();
+        mac.update(salt);
+        mac.update(&(block_index as u32 + 1).to_be_bytes());
+        let mut u = mac.finalize();
+        let mut block = u;
+
+        for round in 1..iterations {
+            if round % ROUNDS_PER_YIELD == 0 {
+                yield_now().await;
+            }
+
+            let mut mac = keyed.clone();
+            mac.update(&u);
+            u = mac.finalize();
+            for (b, x) in block.iter_mut().zip(u.iter()) {
+                *b ^= x;
+            }
+        }
+
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+}
+
+// Compares secrets without bailing out at the first difference, so timing reveals nothing but the length
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut difference = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        difference |= x ^ y;
+    }
+    difference == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test cases 1, 2 and 6
+        assert_eq!(
+            hmac_sha256(&[0x0b; 20], b"Hi There"),
+            [
+                0xb0, 0x34, 0x4c, 0x61, 0xd8, 0xdb, 0x38, 0x53, 0x5c, 0xa8, 0xaf, 0xce, 0xaf, 0x0b, 0xf1, 0x2b,
+                0x88, 0x1d, 0xc2, 0x00, 0xc9, 0x83, 0x3d, 0xa7, 0x26, 0xe9, 0x37, 0x6c, 0x2e, 0x32, 0xcf, 0xf7,
+            ]
+        );
+
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            [
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+                0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+            ]
+        );
+
+        assert_eq!(
+            hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First"),
+            [
+                0x60, 0xe4, 0x31, 0x59, 0x1e, 0xe0, 0xb6, 0x7f, 0x0d, 0x8a, 0x26, 0xaa, 0xcb, 0xf5, 0xb7, 0x7f,
+                0x8e, 0x0b, 0xc6, 0x21, 0x37, 0x28, 0xc5, 0x14, 0x05, 0x46, 0x04, 0x0f, 0x0e, 0xe3, 0x7f, 0x54,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pbkdf2_sha256() {
+        // RFC 7914 section 11, spanning two output blocks
+        let mut output = [0u8; 64];
+        embassy_futures::block_on(pbkdf2_sha256(b"passwd", b"salt", 1, &mut output));
+        assert_eq!(
+            output,
+            [
+                0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f, 0xec, 0x16, 0x91, 0xc2, 0x25, 0x44, 0xb6, 0x05,
+                0xf9, 0x41, 0x85, 0x21, 0x6d, 0xde, 0x04, 0x65, 0xe6, 0x8b, 0x9d, 0x57, 0xc2, 0x0d, 0xac, 0xbc,
+                0x49, 0xca, 0x9c, 0xcc, 0xf1, 0x79, 0xb6, 0x45, 0x99, 0x16, 0x64, 0xb3, 0x9d, 0x77, 0xef, 0x31,
+                0x7c, 0x71, 0xb8, 0x45, 0xb1, 0xe3, 0x0b, 0xd5, 0x09, 0x11, 0x20, 0x41, 0xd3, 0xa1, 0x97, 0x83,
+            ]
+        );
+
+        let mut output = [0u8; 32];
+        embassy_futures::block_on(pbkdf2_sha256(b"password", b"salt", 4096, &mut output));
+        assert_eq!(
+            output,
+            [
+                0xc5, 0xe4, 0x78, 0xd5, 0x92, 0x88, 0xc8, 0x41, 0xaa, 0x53, 0x0d, 0xb6, 0x84, 0x5c, 0x4c, 0x8d,
+                0x96, 0x28, 0x93, 0xa0, 0x01, 0xce, 0x4e, 0x11, 0xa4, 0x96, 0x38, 0x73, 0xaa, 0x98, 0x13, 0x4a,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
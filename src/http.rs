@@ -1,13 +1,18 @@
 use core::fmt;
 use core::fmt::Write;
+use embedded_io_async::Write as AsyncWrite;
+use crate::cookie::{Cookie, Cookies};
+use crate::template::Escape;
+use crate::url::url_decode;
 
-pub const MAX_HEADERS: usize = 16;
+pub const MAX_HEADERS: usize = 24;
 pub const MAX_HEADER_KEY: usize = 32;
 pub const MAX_HEADER_VALUE: usize = 128;
 pub const MAX_HEADER_VALUES: usize = 4;
-pub const BUFFER_SIZE: usize = 1024 * 16;
+pub const BUFFER_SIZE: usize = 1024 * 8;
 pub const MAX_URI_LENGTH: usize = 2048;
 pub const MAX_METHOD_LENGTH: usize = 20;
+pub const MAX_VERSION_LENGTH: usize = 16;
 pub const MAX_PATH_LENGTH: usize = 1024;
 pub const MAX_QUERY_LENGTH: usize = 1024;
 pub const MAX_QUERY_PARAMS: usize = 24;
@@ -15,7 +20,23 @@ pub const MAX_QUERY_PARAM_LENGTH: usize = 128;
 pub const MAX_POST_LENGTH: usize = 1024 * 4;
 pub const MAX_POST_PARAMS: usize = 24;
 pub const MAX_POST_PARAM_LENGTH: usize = 128;
-const MAX_BOUNDARY_COUNT: usize = 32;
+pub const MAX_REQUEST_SIZE: usize = 1024 * 8;
+pub const MAX_HEADER_LINE_LENGTH: usize = 1024;
+pub const MAX_FILE_PATH_LENGTH: usize = 128;
+pub const MAX_CHUNK_SIZE_DIGITS: usize = 8;
+pub const MAX_CHUNK_EXTENSION_LENGTH: usize = 64;
+pub const MAX_TRAILER_SIZE: usize = 256;
+// Cookie, Set-Cookie and Authorization carry login tokens, far longer than any other header
+// value, so only they get this much room
+pub const MAX_TOKEN_HEADER_LENGTH: usize = 512;
+pub const MAX_SET_COOKIES: usize = 4;
+pub const MAX_ROUTES: usize = 32;
+pub const MAX_GUARDS: usize = 8;
+pub const MAX_ROUTE_PARAMS: usize = 4;
+pub const MAX_ROUTE_PARAM_LENGTH: usize = 128;
+pub const MAX_BOUNDARY_LENGTH: usize = 70;
+pub const MAX_PART_HEADER_SIZE: usize = 512;
+pub const MULTIPART_CHUNK_SIZE: usize = 512;
 
 
 #[derive(Copy)]
@@ -67,31 +88,6 @@ impl<const N: usize> ByteString<N> {
         self.length
     }
 
-    pub fn split<F>(&self, predicate: F, output: &mut [Option<Self>])
-        where
-            F: Fn(&u8) -> bool,
-    {
-        let mut start = 0;
-        let mut end = 0;
-        let mut index = 0;
-
-        while end < self.length && index < output.len() {
-            if predicate(&self.data[end]) {
-                if end > start {
-                    output[index] = Some(ByteString::new(&self.data[start..end]));
-                    index += 1;
-                }
-                start = end + 1;
-            }
-            end += 1;
-        }
-
-        if end > start && index < output.len() {
-            output[index] = Some(ByteString::new(&self.data[start..end]));
-        }
-    }
-
-
     pub fn trim(&mut self) {
         // Find the start index of the trimmed string
         let mut start = 0;
@@ -169,6 +165,26 @@ impl<const N: usize, const M: usize> Headers<N, M> {
         };
         append_header(&mut self.data, key, header_values);
     }
+
+    // Header names are case-insensitive, so unlike get_header this matches regardless of case
+    pub fn get(&self, header_name: &[u8]) -> Option<&[u8]> {
+        for (key, _, value) in self.data.iter() {
+            if let (Some(key), Some(value)) = (key, value) {
+                if key.as_bytes().eq_ignore_ascii_case(header_name) {
+                    return Some(value.as_bytes());
+                }
+            }
+        }
+        None
+    }
+
+    // True when a comma separated header such as Connection lists the given token
+    pub fn has_token(&self, header_name: &[u8], token: &[u8]) -> bool {
+        match self.get(header_name) {
+            Some(value) => value.split(|&b| b == b',').any(|item| trim_bytes(item).eq_ignore_ascii_case(token)),
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -184,10 +200,22 @@ impl<'a> Default for QueryParam<'a> {
 }
 
 
+// An SD card file streamed into the body where it was put, so it doesn't have to fit in the buffer
+#[derive(Copy, Clone)]
+pub struct FileBody {
+    pub path: ByteString<MAX_FILE_PATH_LENGTH>,
+    // How much of `body` goes out ahead of the file, the rest follows it
+    pub at: usize,
+    pub escape: Escape,
+}
+
 pub struct Response<const N: usize, const M: usize> {
     pub status: usize,
     pub headers: Headers<N, M>,
     pub body: ByteString<BUFFER_SIZE>,
+    pub file: Option<FileBody>,
+    // Set-Cookie lines, sent after the headers
+    pub cookies: [Option<ByteString<MAX_TOKEN_HEADER_LENGTH>>; MAX_SET_COOKIES],
 }
 
 
@@ -198,6 +226,8 @@ impl<const N: usize, const M: usize> Response<N, M> {
             status: 404, // Default status, can be changed later
             body: ByteString::<BUFFER_SIZE>::new(&[]),
             headers: Headers::<N, M>::new(),
+            file: None,
+            cookies: [None; MAX_SET_COOKIES],
         }
     }
 
@@ -209,14 +239,195 @@ impl<const N: usize, const M: usize> Response<N, M> {
         self.headers.append(key, Some(value));
     }
 
-    pub fn generate(&mut self) -> ([u8; BUFFER_SIZE], usize) {
-        generate_http_response::<N, M>(self.status, &mut self.headers, self.body.as_bytes())
+    // Every cookie gets its own Set-Cookie line, they can't be folded into one
+    pub fn set_cookie(&mut self, cookie: &Cookie) {
+        let mut value = ByteString::<MAX_TOKEN_HEADER_LENGTH>::new(b"");
+        cookie.write_to(&mut value);
+
+        if let Some(slot) = self.cookies.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(value);
+        }
+    }
+
+    pub fn set_cookies(&self) -> impl Iterator<Item = &[u8]> {
+        self.cookies.iter().flatten().map(|value| value.as_bytes())
+    }
+
+    pub fn send_file(&mut self, file_path: &[u8]) {
+        self.embed_file(file_path, Escape::Raw);
+    }
+
+    // Streams the file into the body at this point, escaped for where it lands in the page
+    pub fn embed_file(&mut self, file_path: &[u8], escape: Escape) {
+        self.file = Some(FileBody { path: ByteString::new(file_path), at: self.body.len(), escape });
+    }
+
+    // Writes the buffered body straight to the writer without building the response in memory first
+    pub async fn send<W: AsyncWrite>(&mut self, writer: &mut W) -> Result<(), W::Error> {
+        let mut response_writer = ResponseWriter::start(
+            writer,
+            self.status,
+            &self.headers,
+            &self.cookies,
+            BodyLength::Fixed(self.body.len()),
+        ).await?;
+        response_writer.write_body(self.body.as_bytes()).await?;
+        response_writer.finish().await
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+// Streams a response to the socket: the status line and headers go out first, then the body
+// is written in as many pieces as the caller likes. When the length is not known up front the
+// body is sent with chunked transfer encoding.
+pub struct ResponseWriter<'a, W: AsyncWrite> {
+    writer: &'a mut W,
+    length: BodyLength,
+}
+
+impl<'a, W: AsyncWrite> ResponseWriter<'a, W> {
+    pub async fn start<const N: usize, const M: usize>(
+        writer: &'a mut W,
+        status_code: usize,
+        headers: &Headers<N, M>,
+        cookies: &[Option<ByteString<MAX_TOKEN_HEADER_LENGTH>>],
+        length: BodyLength,
+    ) -> Result<ResponseWriter<'a, W>, W::Error> {
+        let mut status_code_bytes = [0u8; 10];
+        let status_code_length = usize_to_bytes(status_code, &mut status_code_bytes);
+
+        writer.write_all(b"HTTP/1.1 ").await?;
+        writer.write_all(&status_code_bytes[..status_code_length]).await?;
+        writer.write_all(b" ").await?;
+        writer.write_all(get_status_message(status_code).as_bytes()).await?;
+        writer.write_all(b"\r\n").await?;
+
+        for (key, _, value) in headers.data.iter() {
+            if let Some(key) = key {
+                writer.write_all(key.as_bytes()).await?;
+                if let Some(value) = value {
+                    writer.write_all(b": ").await?;
+                    writer.write_all(value.as_bytes()).await?;
+                }
+                writer.write_all(b"\r\n").await?;
+            }
+        }
+
+        for value in cookies.iter().flatten() {
+            writer.write_all(b"Set-Cookie: ").await?;
+            writer.write_all(value.as_bytes()).await?;
+            writer.write_all(b"\r\n").await?;
+        }
+
+        match length {
+            BodyLength::Fixed(content_length) => {
+                let mut content_length_bytes = [0u8; 20];
+                let content_length_len = usize_to_bytes(content_length, &mut content_length_bytes);
+                writer.write_all(b"Content-Length: ").await?;
+                writer.write_all(&content_length_bytes[..content_length_len]).await?;
+                writer.write_all(b"\r\n").await?;
+            }
+            BodyLength::Chunked => {
+                writer.write_all(b"Transfer-Encoding: chunked\r\n").await?;
+            }
+        }
+
+        writer.write_all(b"\r\n").await?;
+
+        Ok(ResponseWriter { writer, length })
+    }
+
+    pub async fn write_body(&mut self, data: &[u8]) -> Result<(), W::Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        match self.length {
+            BodyLength::Fixed(_) => self.writer.write_all(data).await,
+            BodyLength::Chunked => {
+                let mut size_line = [0u8; 18];
+                let size_line_length = chunk_size_line(data.len(), &mut size_line);
+                self.writer.write_all(&size_line[..size_line_length]).await?;
+                self.writer.write_all(data).await?;
+                self.writer.write_all(b"\r\n").await
+            }
+        }
+    }
+
+    pub async fn finish(self) -> Result<(), W::Error> {
+        if self.length == BodyLength::Chunked {
+            self.writer.write_all(b"0\r\n\r\n").await?;
+        }
+        self.writer.flush().await
+    }
+
+    // Ends a chunked body with a trailer section. The trailer names should have been announced
+    // in a `Trailer` header; for a fixed length body there is nowhere to put them so they are dropped.
+    pub async fn finish_with_trailers<const N: usize, const M: usize>(self, trailers: &Headers<N, M>) -> Result<(), W::Error> {
+        if self.length != BodyLength::Chunked {
+            return self.finish().await;
+        }
+
+        self.writer.write_all(b"0\r\n").await?;
+        for (key, _, value) in trailers.data.iter() {
+            if let (Some(key), Some(value)) = (key, value) {
+                self.writer.write_all(key.as_bytes()).await?;
+                self.writer.write_all(b": ").await?;
+                self.writer.write_all(value.as_bytes()).await?;
+                self.writer.write_all(b"\r\n").await?;
+            }
+        }
+        self.writer.write_all(b"\r\n").await?;
+        self.writer.flush().await
+    }
+}
+
+impl<'a, W: AsyncWrite> embedded_io_async::ErrorType for ResponseWriter<'a, W> {
+    type Error = W::Error;
+}
+
+// So templates can render straight into the body with render_async
+impl<'a, W: AsyncWrite> AsyncWrite for ResponseWriter<'a, W> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, W::Error> {
+        self.write_body(buf).await?;
+        Ok(buf.len())
     }
 }
 
+// Formats the hexadecimal size line that precedes every chunk, e.g. `1a2\r\n`
+fn chunk_size_line(size: usize, buffer: &mut [u8; 18]) -> usize {
+    let hex_chars = b"0123456789abcdef";
+    let mut digits = [0u8; 16];
+    let mut digit_count = 0;
+    let mut n = size;
+
+    loop {
+        digits[digit_count] = hex_chars[n & 0x0F];
+        digit_count += 1;
+        n >>= 4;
+        if n == 0 {
+            break;
+        }
+    }
+
+    for i in 0..digit_count {
+        buffer[i] = digits[digit_count - 1 - i];
+    }
+    buffer[digit_count] = b'\r';
+    buffer[digit_count + 1] = b'\n';
+
+    digit_count + 2
+}
+
 pub struct Request {
     pub method: ByteString<MAX_METHOD_LENGTH>,
     pub uri: ByteString<MAX_URI_LENGTH>,
+    pub version: ByteString<MAX_VERSION_LENGTH>,
     pub path: ByteString<MAX_PATH_LENGTH>,
     pub query_param_keys: [ByteString<MAX_QUERY_PARAM_LENGTH>; MAX_QUERY_PARAMS],
     pub query_param_values: [ByteString<MAX_QUERY_PARAM_LENGTH>; MAX_QUERY_PARAMS],
@@ -225,6 +436,11 @@ pub struct Request {
     pub post_param_values: [ByteString<MAX_POST_PARAM_LENGTH>; MAX_POST_PARAMS],
     pub post_param_count: usize,
     pub headers: Headers<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    // Kept whole, `headers` only has the start of values longer than MAX_HEADER_VALUE
+    pub cookie: ByteString<MAX_TOKEN_HEADER_LENGTH>,
+    pub authorization: ByteString<MAX_TOKEN_HEADER_LENGTH>,
+    // IPv4 address of the client, filled in by the server rather than parsed from the request
+    pub remote_address: [u8; 4],
 }
 
 impl Request {
@@ -237,6 +453,7 @@ impl Request {
         Request {
             method: ByteString::new(&[0; MAX_METHOD_LENGTH]),
             uri: ByteString::new(&[0; MAX_URI_LENGTH]),
+            version: ByteString::new(b""),
             path: ByteString::new(&[0; MAX_PATH_LENGTH]),
             query_param_keys,
             query_param_values,
@@ -245,220 +462,1087 @@ impl Request {
             post_param_values,
             post_param_count: 0,
             headers: Headers::new(),
+            cookie: ByteString::new(b""),
+            authorization: ByteString::new(b""),
+            remote_address: [0; 4],
+        }
+    }
+
+    pub(crate) fn parse(&mut self, buf: &[u8], n: usize) -> Result<(), ParseError> {
+        self.parse_head(buf, n)?;
+        let body = request_body(buf);
+
+        if self.method.as_bytes() == b"POST" {
+            let urlencoded = self.headers.get(b"Content-Type")
+                .and_then(|content_type| content_type.split(|&b| b == b';').next())
+                .map_or(false, |media_type| trim_bytes(media_type).eq_ignore_ascii_case(b"application/x-www-form-urlencoded"));
+
+            // One byte over the limit, so an overly long boundary is rejected rather than cut short
+            let boundary = self.multipart_boundary().map(ByteString::<{ MAX_BOUNDARY_LENGTH + 1 }>::new);
+
+            if urlencoded {
+                self.parse_post_url_encoded(body)?;
+            } else if let Some(boundary) = boundary {
+                self.parse_post_multipart(body, boundary.as_bytes())?;
+            } else if self.multipart() {
+                return Err(ParseError::MalformedMultipart);
+            }
         }
+
+        Ok(())
     }
 
-    pub(crate) fn parse(&mut self, buf: &[u8], n: usize) {
+    // The request line and headers only, for when the body is read separately
+    pub(crate) fn parse_head(&mut self, buf: &[u8], n: usize) -> Result<(), ParseError> {
         self.headers.data = parse_http_headers(buf, n, false);
+        self.cookie = ByteString::new(b"");
+        self.authorization = ByteString::new(b"");
+        collect_header(&buf[..n], b"Cookie", &mut self.cookie);
+        collect_header(&buf[..n], b"Authorization", &mut self.authorization);
+
+        // Read the request line from the buffer, a header key is too short to hold a long uri
+        let request_line = &buf[..find_crlf(&buf[..n]).ok_or(ParseError::BadRequestLine)?];
+        let (method, uri) = parse_request_line(request_line).ok_or(ParseError::BadRequestLine)?;
+
+        if method.is_empty() || uri.is_empty() || method.len() > MAX_METHOD_LENGTH || uri.len() > MAX_URI_LENGTH {
+            return Err(ParseError::BadRequestLine);
+        }
+
+        self.method = ByteString::new(method);
+        self.uri = ByteString::new(uri);
+        self.version = ByteString::new(parse_request_version(request_line).unwrap_or(b"HTTP/1.0"));
+
+        let (path, params) = split_path_and_query(&self.uri);
+        self.path = path;
+        self.query_param_count = parse_query_string(&params, &mut self.query_param_keys, &mut self.query_param_values);
+
+        Ok(())
+    }
+
+    // Buffered multipart bodies end up in the post params, each value cut to MAX_POST_PARAM_LENGTH.
+    // Routes that take file uploads should stream the body through a MultipartReader instead.
+    fn parse_post_multipart(&mut self, body: &[u8], boundary: &[u8]) -> Result<(), ParseError> {
+        let mut reader = MultipartReader::new(boundary)?;
+
+        let keys = &mut self.post_param_keys;
+        let values = &mut self.post_param_values;
+        let count = &mut self.post_param_count;
+
+        reader.feed(body, &mut |event| {
+            if *count >= MAX_POST_PARAMS {
+                return;
+            }
+
+            match event {
+                MultipartEvent::Start(part) => {
+                    keys[*count] = ByteString::new(part.name.as_bytes());
+                    values[*count] = ByteString::new(b"");
+                }
+                MultipartEvent::Data(_, data) => values[*count].append(data),
+                MultipartEvent::End(_) => *count += 1,
+            }
+        })?;
+
+        reader.finish()
+    }
+
+    fn parse_post_url_encoded(&mut self, body: &[u8]) -> Result<(), ParseError> {
+        let post_content_length = self.headers.get(b"Content-Length")
+            .and_then(parse_bytes_to_usize)
+            .unwrap_or(body.len());
+
+        if post_content_length > MAX_POST_LENGTH {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        if body.len() >= post_content_length {
+            let post_data = ByteString::<MAX_POST_LENGTH>::new(&body[..post_content_length]);
+            self.post_param_count = parse_query_string(&post_data, &mut self.post_param_keys, &mut self.post_param_values);
+            Ok(())
+        } else {
+            Err(ParseError::MalformedBody)
+        }
+    }
+
+    // HTTP/1.1 connections persist unless the client asks to close, HTTP/1.0 ones only when asked to
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_token(b"Connection", b"close") {
+            return false;
+        }
+
+        if self.version.as_bytes() == b"HTTP/1.1" {
+            true
+        } else {
+            self.headers.has_token(b"Connection", b"keep-alive")
+        }
+    }
+
+    // Adds headers that arrived after the body, such as the trailer section of a chunked request
+    pub fn append_headers(&mut self, buf: &[u8]) {
+        let trailers: [Header<MAX_HEADER_KEY, MAX_HEADER_VALUE>; MAX_HEADERS] = parse_http_headers(buf, buf.len(), true);
+
+        for (key, _, value) in trailers.iter() {
+            if let Some(key) = key {
+                self.headers.append(*key, *value);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        get_query_param_value(self.query_param_count, &self.query_param_keys, &self.query_param_values, key)
+    }
+
+    pub fn post(&self, key: &[u8]) -> Option<&[u8]> {
+        get_query_param_value(self.post_param_count, &self.post_param_keys, &self.post_param_values, &key)
+    }
+
+    // Looks up a cookie sent by the browser, across all Cookie headers
+    pub fn cookie(&self, name: &[u8]) -> Option<&[u8]> {
+        self.cookies().find(|(cookie_name, _)| *cookie_name == name).map(|(_, value)| value)
+    }
+
+    pub fn cookies(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        Cookies::new(self.cookie.as_bytes())
+    }
+
+    pub fn authorization(&self) -> Option<&[u8]> {
+        if self.authorization.len() == 0 { None } else { Some(self.authorization.as_bytes()) }
+    }
+
+    pub fn multipart(&self) -> bool {
+        self.headers.get(b"Content-Type")
+            .and_then(|content_type| content_type.split(|&b| b == b';').next())
+            .map_or(false, |media_type| trim_bytes(media_type).eq_ignore_ascii_case(b"multipart/form-data"))
+    }
+
+    // The boundary of a multipart/form-data body, None for any other kind of body
+    pub fn multipart_boundary(&self) -> Option<&[u8]> {
+        if self.multipart() {
+            header_param(self.headers.get(b"Content-Type")?, b"boundary")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ParsePhase {
+    RequestLine,
+    Headers,
+    Body,
+    Complete,
+}
 
-        if !self.headers.data.is_empty() {
-            // Assuming headers[0].0 contains the request line
-            if let Some(key) = self.headers.data[0].0 {
-                if let Some((method, uri)) = parse_request_line(key.as_bytes()) {
-                    // Process method and uri immediately within the scope
-                    self.method = ByteString::new(method);
-                    self.uri = ByteString::new(uri);
-
-                    let (path, params) = split_path_and_query(&self.uri);
-                    self.path = path;
-                    self.query_param_count = parse_query_string(&params, &mut self.query_param_keys, &mut self.query_param_values);
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ParseStatus {
+    Incomplete,
+    Complete,
+    // The header section of a multipart request is in, see RequestParser::stream_multipart
+    HeadComplete,
+    Error(ParseError),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ParseError {
+    BadRequestLine,
+    MalformedHeader,
+    HeaderTooLong,
+    TooManyHeaders,
+    MalformedBody,
+    BodyTooLarge,
+    MalformedMultipart,
+}
+
+impl ParseError {
+    // The status code to answer with before closing the connection
+    pub fn status(&self) -> usize {
+        match self {
+            ParseError::HeaderTooLong | ParseError::TooManyHeaders => 431,
+            ParseError::BodyTooLarge => 413,
+            _ => 400,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ChunkState {
+    Size,
+    Extension,
+    SizeLf,
+    Data,
+    DataCr,
+    DataLf,
+    TrailerLineStart,
+    Trailer,
+    TrailerLf,
+    EndLf,
+    Done,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ChunkedError {
+    InvalidSizeLine,
+    InvalidChunkEnd,
+    InvalidTrailer,
+    TrailerTooLarge,
+}
+
+// Decodes a `Transfer-Encoding: chunked` body one byte at a time. Data bytes are handed back
+// to the caller, framing is validated strictly and the trailer section is kept so it can be
+// merged into the request headers once the body is done.
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    remaining: usize,
+    size_digits: usize,
+    extension_length: usize,
+    trailer_has_colon: bool,
+    trailers: ByteString<MAX_TRAILER_SIZE>,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: ChunkState::Size,
+            remaining: 0,
+            size_digits: 0,
+            extension_length: 0,
+            trailer_has_colon: false,
+            trailers: ByteString::new(b""),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    pub fn trailers(&self) -> &[u8] {
+        self.trailers.as_bytes()
+    }
+
+    pub fn push(&mut self, byte: u8) -> Result<Option<u8>, ChunkedError> {
+        match self.state {
+            ChunkState::Size => {
+                if let Some(digit) = hex_value(byte) {
+                    if self.size_digits >= MAX_CHUNK_SIZE_DIGITS {
+                        return Err(ChunkedError::InvalidSizeLine);
+                    }
+                    self.remaining = self.remaining * 16 + digit;
+                    self.size_digits += 1;
+                } else if self.size_digits == 0 {
+                    return Err(ChunkedError::InvalidSizeLine);
+                } else if byte == b';' {
+                    self.state = ChunkState::Extension;
+                } else if byte == b'\r' {
+                    self.state = ChunkState::SizeLf;
                 } else {
-                    // Handle the case where parse_request_line returns None
-                    // This might involve setting default values, logging an error, etc.
+                    return Err(ChunkedError::InvalidSizeLine);
                 }
             }
+            ChunkState::Extension => {
+                if byte == b'\r' {
+                    self.state = ChunkState::SizeLf;
+                } else if (byte < 0x20 && byte != b'\t') || byte == 0x7F {
+                    return Err(ChunkedError::InvalidSizeLine);
+                } else {
+                    self.extension_length += 1;
+                    if self.extension_length > MAX_CHUNK_EXTENSION_LENGTH {
+                        return Err(ChunkedError::InvalidSizeLine);
+                    }
+                }
+            }
+            ChunkState::SizeLf => {
+                if byte != b'\n' {
+                    return Err(ChunkedError::InvalidSizeLine);
+                }
+                self.state = if self.remaining == 0 {
+                    ChunkState::TrailerLineStart
+                } else {
+                    ChunkState::Data
+                };
+            }
+            ChunkState::Data => {
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    self.state = ChunkState::DataCr;
+                }
+                return Ok(Some(byte));
+            }
+            ChunkState::DataCr => {
+                if byte != b'\r' {
+                    return Err(ChunkedError::InvalidChunkEnd);
+                }
+                self.state = ChunkState::DataLf;
+            }
+            ChunkState::DataLf => {
+                if byte != b'\n' {
+                    return Err(ChunkedError::InvalidChunkEnd);
+                }
+                self.size_digits = 0;
+                self.extension_length = 0;
+                self.state = ChunkState::Size;
+            }
+            ChunkState::TrailerLineStart => {
+                if byte == b'\r' {
+                    self.state = ChunkState::EndLf;
+                } else {
+                    self.trailer_has_colon = false;
+                    self.state = ChunkState::Trailer;
+                    self.push_trailer_byte(byte)?;
+                }
+            }
+            ChunkState::Trailer => {
+                if byte == b'\r' {
+                    self.state = ChunkState::TrailerLf;
+                } else {
+                    self.push_trailer_byte(byte)?;
+                }
+            }
+            ChunkState::TrailerLf => {
+                if byte != b'\n' || !self.trailer_has_colon {
+                    return Err(ChunkedError::InvalidTrailer);
+                }
+                if self.trailers.len() + 2 > MAX_TRAILER_SIZE {
+                    return Err(ChunkedError::TrailerTooLarge);
+                }
+                self.trailers.append(b"\r\n");
+                self.state = ChunkState::TrailerLineStart;
+            }
+            ChunkState::EndLf => {
+                if byte != b'\n' {
+                    return Err(ChunkedError::InvalidTrailer);
+                }
+                self.state = ChunkState::Done;
+            }
+            ChunkState::Done => {}
+        }
 
+        Ok(None)
+    }
 
-            if self.method.as_bytes() == b"POST" {
-                let (content_type, boundary): (ByteString<128>, ByteString<128>) = match get_header(self.headers.data, b"Content-Type") {
-                    None => (ByteString::new(b""), ByteString::new(b"")),
-                    Some(content_types) => {
-                        let content_type = content_types.unwrap_or_else(|| ByteString::new(b""));
+    fn push_trailer_byte(&mut self, byte: u8) -> Result<(), ChunkedError> {
+        if byte == b'\n' {
+            return Err(ChunkedError::InvalidTrailer);
+        }
+        if self.trailers.len() >= MAX_TRAILER_SIZE {
+            return Err(ChunkedError::TrailerTooLarge);
+        }
+        if byte == b':' {
+            self.trailer_has_colon = true;
+        }
+        self.trailers.append(&[byte]);
+        Ok(())
+    }
+}
 
-                        if content_type.as_bytes() == b"application/x-www-form-urlencoded" {
-                            (content_type, ByteString::new(b""))
-                        } else {
-                            let mut content_type_pairs: [Option<ByteString<128>>; 2] = Default::default();
-                            content_type.split(|&b| b == b';', &mut content_type_pairs);
-
-                            if let [Some(lead), Some(mut trail)] = &content_type_pairs {
-                                trail.trim();
-
-                                let mut boundary_pairs: [Option<ByteString<128>>; 2] = Default::default();
-                                trail.split(|&b| b == b'=', &mut boundary_pairs);
-
-                                if let [Some(_), Some(boundary_value)] = &boundary_pairs {
-                                    (lead.clone(), boundary_value.clone())
-                                } else {
-                                    (ByteString::new(b""), ByteString::new(b""))
-                                }
-                            } else {
-                                // Your code for when there are not enough pairs
-                                (ByteString::new(b""), ByteString::new(b""))
-                            }
+fn hex_value(byte: u8) -> Option<usize> {
+    match byte {
+        b'0'..=b'9' => Some((byte - b'0') as usize),
+        b'a'..=b'f' => Some((byte - b'a' + 10) as usize),
+        b'A'..=b'F' => Some((byte - b'A' + 10) as usize),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum MultipartState {
+    Preamble,
+    BoundaryEnd,
+    Headers,
+    Data,
+    Done,
+}
+
+pub struct MultipartPart {
+    pub name: ByteString<MAX_POST_PARAM_LENGTH>,
+    pub filename: Option<ByteString<MAX_FILE_PATH_LENGTH>>,
+    pub content_type: ByteString<MAX_HEADER_VALUE>,
+}
+
+pub enum MultipartEvent<'a> {
+    Start(&'a MultipartPart),
+    Data(&'a MultipartPart, &'a [u8]),
+    End(&'a MultipartPart),
+}
+
+// Splits a multipart/form-data body into parts as it arrives, so no part ever has to fit in
+// memory. The body can be fed in pieces of any size and events are handed to the callback in
+// order, part data in pieces of at most MULTIPART_CHUNK_SIZE bytes.
+pub struct MultipartReader {
+    // CRLF "--" boundary, the line break before a boundary belongs to the delimiter, not the data
+    delimiter: ByteString<{ MAX_BOUNDARY_LENGTH + 4 }>,
+    matched: usize,
+    state: MultipartState,
+    boundary_end: [u8; 2],
+    boundary_end_length: usize,
+    head: [u8; MAX_PART_HEADER_SIZE],
+    head_length: usize,
+    data: [u8; MULTIPART_CHUNK_SIZE],
+    data_length: usize,
+    part: MultipartPart,
+}
+
+impl MultipartReader {
+    pub fn new(boundary: &[u8]) -> Result<MultipartReader, ParseError> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LENGTH || boundary.iter().any(|&b| b == b'\r' || b == b'\n') {
+            return Err(ParseError::MalformedMultipart);
+        }
+
+        let mut delimiter = ByteString::new(b"\r\n--");
+        delimiter.append(boundary);
+
+        Ok(MultipartReader {
+            delimiter,
+            // The body opens with the first boundary, as if the line break had already been seen
+            matched: 2,
+            state: MultipartState::Preamble,
+            boundary_end: [0; 2],
+            boundary_end_length: 0,
+            head: [0; MAX_PART_HEADER_SIZE],
+            head_length: 0,
+            data: [0; MULTIPART_CHUNK_SIZE],
+            data_length: 0,
+            part: MultipartPart {
+                name: ByteString::new(b""),
+                filename: None,
+                content_type: ByteString::new(b""),
+            },
+        })
+    }
+
+    pub fn feed<F>(&mut self, input: &[u8], on_event: &mut F) -> Result<(), ParseError>
+        where
+            F: FnMut(MultipartEvent),
+    {
+        for &byte in input {
+            self.push(byte, on_event)?;
+        }
+
+        // Hand over what has been collected so data never waits on the next read
+        self.flush(on_event);
+        Ok(())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == MultipartState::Done
+    }
+
+    // Called once the whole body has been fed, a body without its closing boundary is truncated
+    pub fn finish(&self) -> Result<(), ParseError> {
+        if self.is_done() {
+            Ok(())
+        } else {
+            Err(ParseError::MalformedMultipart)
+        }
+    }
+
+    fn push<F>(&mut self, byte: u8, on_event: &mut F) -> Result<(), ParseError>
+        where
+            F: FnMut(MultipartEvent),
+    {
+        match self.state {
+            MultipartState::Preamble | MultipartState::Data => {
+                let delimiter = self.delimiter;
+                let delimiter = delimiter.as_bytes();
+
+                if byte == delimiter[self.matched] {
+                    self.matched += 1;
+
+                    if self.matched == delimiter.len() {
+                        if self.state == MultipartState::Data {
+                            self.flush(on_event);
+                            on_event(MultipartEvent::End(&self.part));
                         }
+                        self.matched = 0;
+                        self.boundary_end_length = 0;
+                        self.state = MultipartState::BoundaryEnd;
                     }
-                };
+                    return Ok(());
+                }
 
+                // A partial match was data after all. The boundary can't contain a CR, so the
+                // current byte can only restart a match at the very beginning of the delimiter.
+                if self.state == MultipartState::Data {
+                    for &matched_byte in &delimiter[..self.matched] {
+                        self.push_data(matched_byte, on_event);
+                    }
+                }
 
-                match content_type.as_bytes() {
-                    b"application/x-www-form-urlencoded" => {
-                        self.parse_post_url_encoded(&buf);
+                if byte == delimiter[0] {
+                    self.matched = 1;
+                } else {
+                    self.matched = 0;
+                    if self.state == MultipartState::Data {
+                        self.push_data(byte, on_event);
                     }
-                    b"multipart/form-data" => {
-                        // Grab Content Length
-                        let content_length = match get_header(self.headers.data, b"Content-Length") {
-                            Some(content_lengths) => {
-                                let content_length = match parse_bytes_to_usize(content_lengths.unwrap_or_else(|| ByteString::new(b"0")).as_bytes()) {
-                                    Some(length) => length,
-                                    _ => 0,
-                                };
-                                content_length
-                            }
-                            _ => 0
-                        };
+                }
+            }
+            MultipartState::BoundaryEnd => {
+                self.boundary_end[self.boundary_end_length] = byte;
+                self.boundary_end_length += 1;
+
+                if self.boundary_end_length == 2 {
+                    match &self.boundary_end {
+                        b"--" => self.state = MultipartState::Done,
+                        b"\r\n" => {
+                            // Keep the line break so the part headers look like a request head
+                            self.head[..2].copy_from_slice(b"\r\n");
+                            self.head_length = 2;
+                            self.state = MultipartState::Headers;
+                        }
+                        _ => return Err(ParseError::MalformedMultipart),
+                    }
+                }
+            }
+            MultipartState::Headers => {
+                if self.head_length >= MAX_PART_HEADER_SIZE {
+                    return Err(ParseError::HeaderTooLong);
+                }
 
-                        // Calculate the start index for the slice
-                        let start_index = if content_length > buf.len() {
-                            0 // If Content-Length is greater than the buffer size, start from the beginning
-                        } else {
-                            buf.len() - content_length // Otherwise, start from buf.len() - content_length
-                        };
+                self.head[self.head_length] = byte;
+                self.head_length += 1;
 
-                        // let multipart_buff = &buf[start_index..];
+                if self.head[..self.head_length].ends_with(b"\r\n\r\n") {
+                    self.start_part()?;
+                    on_event(MultipartEvent::Start(&self.part));
+                    self.state = MultipartState::Data;
+                }
+            }
+            MultipartState::Done => {}
+        }
+
+        Ok(())
+    }
 
-                        let mut delimited_boundary = ByteString::<128>::new(b"--");
-                        delimited_boundary.append(boundary.as_bytes());
+    fn start_part(&mut self) -> Result<(), ParseError> {
+        let head = &self.head[..self.head_length];
 
-                        let mut delimited_boundary_end = ByteString::<128>::new(b"--");
-                        delimited_boundary_end.append(boundary.as_bytes());
-                        delimited_boundary_end.append(b"--");
+        let disposition = find_header_value(head, b"Content-Disposition").ok_or(ParseError::MalformedMultipart)?;
+        if !disposition.split(|&b| b == b';').next().map_or(false, |kind| trim_bytes(kind).eq_ignore_ascii_case(b"form-data")) {
+            return Err(ParseError::MalformedMultipart);
+        }
 
-                        let delimited_boundary_end_str = core::str::from_utf8(delimited_boundary_end.as_bytes())
-                            .expect("Invalid UTF-8");
+        let name = header_param(disposition, b"name").ok_or(ParseError::MalformedMultipart)?;
+
+        self.part.name = ByteString::new(name);
+        self.part.filename = header_param(disposition, b"filename").map(ByteString::new);
+        self.part.content_type = ByteString::new(find_header_value(head, b"Content-Type").unwrap_or(b"text/plain"));
+
+        Ok(())
+    }
+
+    fn push_data<F>(&mut self, byte: u8, on_event: &mut F)
+        where
+            F: FnMut(MultipartEvent),
+    {
+        self.data[self.data_length] = byte;
+        self.data_length += 1;
+
+        if self.data_length == MULTIPART_CHUNK_SIZE {
+            self.flush(on_event);
+        }
+    }
+
+    fn flush<F>(&mut self, on_event: &mut F)
+        where
+            F: FnMut(MultipartEvent),
+    {
+        if self.data_length > 0 {
+            on_event(MultipartEvent::Data(&self.part, &self.data[..self.data_length]));
+            self.data_length = 0;
+        }
+    }
+}
+
+// Looks up a parameter such as `name` in `form-data; name="file"`, with the quotes removed
+fn header_param<'a>(value: &'a [u8], param_name: &[u8]) -> Option<&'a [u8]> {
+    value.split(|&b| b == b';').skip(1).find_map(|param| {
+        let param = trim_bytes(param);
+        let equals_index = param.iter().position(|&b| b == b'=')?;
+
+        if trim_bytes(&param[..equals_index]).eq_ignore_ascii_case(param_name) {
+            Some(trim_quotes(trim_bytes(&param[equals_index + 1..])))
+        } else {
+            None
+        }
+    })
+}
+
+// Accumulates a request that may arrive over several TCP reads. Bytes are fed in
+// as they come off the socket and the parser tracks which part of the message it
+// is waiting on, so a Request is only built once the whole message is buffered.
+pub struct RequestParser {
+    buffer: [u8; MAX_REQUEST_SIZE],
+    length: usize,
+    phase: ParsePhase,
+    line_start: usize,
+    header_end: usize,
+    header_count: usize,
+    content_length: usize,
+    chunked: bool,
+    stream_multipart: bool,
+    decoder: ChunkedDecoder,
+    // Chunked bodies are decoded in place, the raw position always runs ahead of the decoded length
+    raw_position: usize,
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        RequestParser {
+            buffer: [0; MAX_REQUEST_SIZE],
+            length: 0,
+            phase: ParsePhase::RequestLine,
+            line_start: 0,
+            header_end: 0,
+            header_count: 0,
+            content_length: 0,
+            chunked: false,
+            stream_multipart: false,
+            decoder: ChunkedDecoder::new(),
+            raw_position: 0,
+        }
+    }
+
+    pub fn phase(&self) -> ParsePhase {
+        self.phase
+    }
+
+    // When enabled, feed stops with HeadComplete once the headers of a multipart request with a
+    // Content-Length are in. The caller then either reads the body itself or calls buffer_body.
+    pub fn stream_multipart(&mut self, enabled: bool) {
+        self.stream_multipart = enabled;
+    }
+
+    pub fn reset(&mut self) {
+        self.length = 0;
+        self.phase = ParsePhase::RequestLine;
+        self.line_start = 0;
+        self.header_end = 0;
+        self.header_count = 0;
+        self.content_length = 0;
+        self.chunked = false;
+        self.decoder = ChunkedDecoder::new();
+        self.raw_position = 0;
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> ParseStatus {
+        if self.phase == ParsePhase::Complete {
+            return ParseStatus::Complete;
+        }
 
-                        let end_pos = match core::str::from_utf8(buf).expect("Invalid UTF-8")
-                            .rfind(delimited_boundary_end_str)
-                        {
-                            Some(pos) => pos,
-                            None => buf.len(), // If not found, assume it's the end of the buffer
+        if self.length + chunk.len() > MAX_REQUEST_SIZE {
+            return match self.phase {
+                ParsePhase::RequestLine | ParsePhase::Headers => ParseStatus::Error(ParseError::HeaderTooLong),
+                _ => ParseStatus::Error(ParseError::BodyTooLarge),
+            };
+        }
+
+        self.buffer[self.length..self.length + chunk.len()].copy_from_slice(chunk);
+        self.length += chunk.len();
+
+        loop {
+            match self.phase {
+                ParsePhase::RequestLine | ParsePhase::Headers => {
+                    let line_end = match find_crlf(&self.buffer[self.line_start..self.length]) {
+                        Some(index) => self.line_start + index,
+                        None if self.length - self.line_start > MAX_HEADER_LINE_LENGTH => {
+                            return ParseStatus::Error(match self.phase {
+                                ParsePhase::RequestLine => ParseError::BadRequestLine,
+                                _ => ParseError::HeaderTooLong,
+                            });
+                        }
+                        None => return ParseStatus::Incomplete,
+                    };
+                    let line = &self.buffer[self.line_start..line_end];
+
+                    if line_end - self.line_start > MAX_HEADER_LINE_LENGTH {
+                        return ParseStatus::Error(match self.phase {
+                            ParsePhase::RequestLine => ParseError::BadRequestLine,
+                            _ => ParseError::HeaderTooLong,
+                        });
+                    }
+
+                    if self.phase == ParsePhase::RequestLine {
+                        if parse_request_line(line).is_none() || parse_request_version(line).is_none() {
+                            return ParseStatus::Error(ParseError::BadRequestLine);
+                        }
+                        self.phase = ParsePhase::Headers;
+                    } else if line_end != self.line_start {
+                        // The request line takes the first slot of the header array
+                        self.header_count += 1;
+                        if self.header_count >= MAX_HEADERS {
+                            return ParseStatus::Error(ParseError::TooManyHeaders);
+                        }
+
+                        let colon_index = match line.iter().position(|&b| b == b':') {
+                            Some(colon_index) => colon_index,
+                            None => return ParseStatus::Error(ParseError::MalformedHeader),
                         };
+                        if trim_bytes(&line[..colon_index]).len() > MAX_HEADER_KEY {
+                            return ParseStatus::Error(ParseError::HeaderTooLong);
+                        }
+                    } else {
+                        // Blank line, the header section is done
+                        self.header_end = line_end + 2;
+                        let head = &self.buffer[..self.header_end];
+
+                        // Transfer-Encoding takes precedence over Content-Length, and chunked must be the final coding
+                        if let Some(transfer_encoding) = find_header_value(head, b"Transfer-Encoding") {
+                            let final_coding = transfer_encoding.rsplit(|&b| b == b',').next().unwrap_or(b"");
+                            if !trim_bytes(final_coding).eq_ignore_ascii_case(b"chunked") {
+                                return ParseStatus::Error(ParseError::MalformedHeader);
+                            }
+                            self.chunked = true;
+                            self.raw_position = self.header_end;
+                        } else {
+                            self.content_length = match find_header_value(head, b"Content-Length") {
+                                Some(value) => match parse_bytes_to_usize(value) {
+                                    Some(content_length) => content_length,
+                                    // All digits, only too long to count
+                                    None if !value.is_empty() && value.iter().all(u8::is_ascii_digit) => {
+                                        return ParseStatus::Error(ParseError::BodyTooLarge);
+                                    }
+                                    None => return ParseStatus::Error(ParseError::MalformedHeader),
+                                },
+                                None => 0,
+                            };
 
-                        let multipart_buff = &buf[start_index..end_pos];
+                            let multipart = find_header_value(head, b"Content-Type")
+                                .map_or(false, |content_type| content_type.len() >= 19 && content_type[..19].eq_ignore_ascii_case(b"multipart/form-data"));
 
-                        // println!("multipart_buff:\n{}", core::str::from_utf8(&multipart_buff[..multipart_buff.len()]).unwrap_or("<invalid UTF-8>"));
+                            if self.stream_multipart && multipart {
+                                self.phase = ParsePhase::Body;
+                                self.line_start = line_end + 2;
+                                return ParseStatus::HeadComplete;
+                            }
+
+                            // The head is in the buffer, so this side can't wrap the way adding could
+                            if self.content_length > MAX_REQUEST_SIZE - self.header_end {
+                                return ParseStatus::Error(ParseError::BodyTooLarge);
+                            }
+                        }
 
-                        // println!("delimited_boundary:\n{}", core::str::from_utf8(&delimited_boundary.as_bytes()).unwrap_or("<invalid UTF-8>"));
-                        let positions = find_boundary_positions(multipart_buff, delimited_boundary.as_bytes());
+                        self.phase = ParsePhase::Body;
+                    }
 
-                        for (index, &pos) in positions.iter().enumerate() {
-                            if index > 0 && pos == 0 {
-                                // If the current position is zero, it indicates the end of boundaries
-                                break;
+                    self.line_start = line_end + 2;
+                }
+                ParsePhase::Body if self.chunked => {
+                    while self.raw_position < self.length && !self.decoder.is_done() {
+                        let byte = self.buffer[self.raw_position];
+                        self.raw_position += 1;
+
+                        match self.decoder.push(byte) {
+                            Ok(Some(data)) => {
+                                self.buffer[self.header_end + self.content_length] = data;
+                                self.content_length += 1;
                             }
+                            Ok(None) => {}
+                            Err(ChunkedError::TrailerTooLarge) => return ParseStatus::Error(ParseError::HeaderTooLong),
+                            Err(_) => return ParseStatus::Error(ParseError::MalformedBody),
+                        }
+                    }
 
-                            // println!("Boundary {} found at position: {}", index + 1, pos);
+                    if !self.decoder.is_done() {
+                        return ParseStatus::Incomplete;
+                    }
+                    self.phase = ParsePhase::Complete;
+                }
+                ParsePhase::Body => {
+                    if self.length - self.header_end < self.content_length {
+                        return ParseStatus::Incomplete;
+                    }
+                    self.phase = ParsePhase::Complete;
+                }
+                ParsePhase::Complete => return ParseStatus::Complete,
+            }
+        }
+    }
 
-                            // Calculate the end position for the current boundary
-                            let end_pos = if index + 1 < positions.len() && positions[index + 1] > 0 {
-                                positions[index + 1]
-                            } else {
-                                multipart_buff.len() // Assume it's the end if there is no next position
-                            };
+    // True when no bytes of a following request have been received yet
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
 
-                            // Get a slice of the buffer to parse inside the boundary
-                            let boundary_slice: &[u8] = &multipart_buff[(pos + delimited_boundary.len() + 2)..end_pos];
+    // Drops the completed request and starts on whatever was pipelined behind it
+    pub fn next_request(&mut self) -> ParseStatus {
+        let message_end = if self.phase != ParsePhase::Complete {
+            0
+        } else if self.chunked {
+            self.raw_position
+        } else {
+            self.header_end + self.content_length
+        };
 
-                            let separator = b"\r\n\r\n";
+        let leftover = self.length - message_end;
+        self.buffer.copy_within(message_end..self.length, 0);
+        self.reset();
+        self.length = leftover;
 
-                            if let Some(index) = boundary_slice.windows(separator.len()).position(|window| window == separator) {
-                                // Found the position of \r\n\r\n
-                                let first_slice = &boundary_slice[0..index + 2];
-                                let second_slice = &boundary_slice[index + separator.len()..boundary_slice.len() - 2];
+        self.feed(&[])
+    }
 
-                                let boundary_headers: [Header<32, 128>; 16] = parse_http_headers(first_slice, first_slice.len(), true);
+    // The complete message, excluding anything that arrived after the body. Chunked bodies
+    // have already been decoded, so the body directly follows the header section.
+    pub fn message(&self) -> &[u8] {
+        match self.phase {
+            ParsePhase::Complete => &self.buffer[..self.header_end + self.content_length],
+            _ => &self.buffer[..self.length],
+        }
+    }
 
-                                match get_header(boundary_headers, b"Content-Disposition") {
-                                    None => {
-                                        // println!("Content Disposition not found!");
-                                    }
-                                    Some(dispositions) => {
-                                        let disposition = dispositions.unwrap_or_else(|| ByteString::new(b""));
-                                        let disposition_bytes = disposition.as_bytes();
+    // Carries on after HeadComplete when the body should be buffered like any other
+    pub fn buffer_body(&mut self) -> ParseStatus {
+        if self.content_length > MAX_REQUEST_SIZE - self.header_end {
+            return ParseStatus::Error(ParseError::BodyTooLarge);
+        }
+        self.feed(&[])
+    }
 
-                                        // println!("disposition:\n'{}'", core::str::from_utf8(disposition.as_bytes()).unwrap_or("<invalid UTF-8>"));
+    pub fn content_length(&self) -> usize {
+        self.content_length
+    }
 
+    // Whatever part of the body has been received so far
+    pub fn body(&self) -> &[u8] {
+        // A streamed body may be announced as longer than anything the buffer could hold
+        let end = core::cmp::min(self.length, self.header_end.saturating_add(self.content_length));
+        &self.buffer[self.header_end..end]
+    }
 
-                                        let mut form_data_pairs: [Option<ByteString<128>>; 2] = Default::default();
-                                        disposition.split(|&b| b == b';', &mut form_data_pairs);
+    pub fn parse_head(&self, req: &mut Request) -> Result<(), ParseError> {
+        let head = &self.buffer[..self.header_end];
+        req.parse_head(head, head.len())
+    }
 
+    pub fn parse(&self, req: &mut Request) -> Result<(), ParseError> {
+        let message = self.message();
+        req.parse(message, message.len())?;
 
-                                        if let [Some(_), Some(mut name)] = &form_data_pairs {
-                                            let mut value_pairs: [Option<ByteString<128>>; 2] = Default::default();
-                                            name.split(|&b| b == b'=', &mut value_pairs);
+        if self.chunked {
+            req.append_headers(self.decoder.trailers());
+        }
+        Ok(())
+    }
+}
 
-                                            if let [Some(_), Some(mut form_data_name)] = &value_pairs {
-                                                // println!("form_data_name:\n'{}'", core::str::from_utf8(form_data_name.as_bytes()).unwrap_or("<invalid UTF-8>"));
+pub type Handler<S> = fn(&Request, &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, &RouteParams, &mut S);
 
-                                                let clean_name = trim_quotes(form_data_name.as_bytes());
+// Receives a streamed multipart body part by part, before the route's handler builds the response.
+// An error ends the upload, it is answered with a 500 and the route's handler isn't run.
+pub type PartHandler<S> = fn(&Request, &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, &RouteParams, &MultipartEvent, &mut S) -> Result<(), &'static str>;
 
-                                                // println!("form_data_clean_name:\n'{}'", core::str::from_utf8(clean_name).unwrap_or("<invalid UTF-8>"));
+// Runs before a route's handlers, returning false after answering the request itself when it
+// may not go further
+pub type Guard<S> = fn(&Request, &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, &mut S) -> bool;
 
+pub struct RouteParams {
+    names: [&'static str; MAX_ROUTE_PARAMS],
+    values: [ByteString<MAX_ROUTE_PARAM_LENGTH>; MAX_ROUTE_PARAMS],
+    count: usize,
+}
 
-                                                self.post_param_keys[self.post_param_count] = ByteString::new(clean_name);
-                                                self.post_param_values[self.post_param_count] = ByteString::new(second_slice);
-                                                self.post_param_count += 1;
-                                            } else {
-                                            }
-                                        } else {
-                                        }
-                                    }
-                                }
-                            } else {
-                                // \r\n\r\n not found in the byte array
-                                // Handle this case accordingly
-                            }
-                            // parse_http_headers
+impl RouteParams {
+    pub fn new() -> RouteParams {
+        RouteParams {
+            names: [""; MAX_ROUTE_PARAMS],
+            values: [ByteString::new(b""); MAX_ROUTE_PARAMS],
+            count: 0,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        for i in 0..self.count {
+            if self.names[i] == name {
+                return Some(self.values[i].as_bytes());
+            }
+        }
+        None
+    }
+
+    fn push(&mut self, name: &'static str, value: &[u8]) -> bool {
+        if self.count >= MAX_ROUTE_PARAMS {
+            return false;
+        }
+        self.names[self.count] = name;
+        self.values[self.count] = ByteString::new(value);
+        self.count += 1;
+        true
+    }
+
+    fn clear(&mut self) {
+        self.count = 0;
+    }
+}
+
+struct Route<S> {
+    method: &'static str,
+    pattern: &'static str,
+    handler: Handler<S>,
+    part_handler: Option<PartHandler<S>>,
+}
+
+// Maps (method, pattern) pairs to handlers. Patterns are matched segment by segment,
+// `:name` captures a single segment and a trailing `*name` captures the rest of the path.
+pub struct Router<S> {
+    routes: [Option<Route<S>>; MAX_ROUTES],
+    count: usize,
+    // (prefix, guard), looked up by the request path whenever a route is about to run
+    guards: [Option<(&'static str, Guard<S>)>; MAX_GUARDS],
+}
+
+impl<S> Router<S> {
+    pub fn new() -> Router<S> {
+        Router {
+            routes: core::array::from_fn(|_| None),
+            count: 0,
+            guards: [None; MAX_GUARDS],
+        }
+    }
+
+    pub fn add(&mut self, method: &'static str, pattern: &'static str, handler: Handler<S>) -> Result<(), &'static str> {
+        self.push(Route { method, pattern, handler, part_handler: None })
+    }
+
+    pub fn get(&mut self, pattern: &'static str, handler: Handler<S>) -> Result<(), &'static str> {
+        self.add("GET", pattern, handler)
+    }
+
+    pub fn post(&mut self, pattern: &'static str, handler: Handler<S>) -> Result<(), &'static str> {
+        self.add("POST", pattern, handler)
+    }
+
+    // A POST route whose multipart body is streamed to part_handler instead of being buffered
+    pub fn upload(&mut self, pattern: &'static str, part_handler: PartHandler<S>, handler: Handler<S>) -> Result<(), &'static str> {
+        self.push(Route { method: "POST", pattern, handler, part_handler: Some(part_handler) })
+    }
+
+    // Puts every path under `prefix` behind the guard, whatever the method and whenever its route
+    // is registered. A prefix covers itself and the paths below it, "/admin" guards "/admin/x" but
+    // not "/administer". Where prefixes overlap the longest one decides, guarding a prefix again
+    // replaces its guard.
+    pub fn guard(&mut self, prefix: &'static str, guard: Guard<S>) -> Result<(), &'static str> {
+        let index = self.guards.iter()
+            .position(|slot| slot.map_or(false, |(guarded, _)| guarded == prefix))
+            .or_else(|| self.guards.iter().position(|slot| slot.is_none()));
+
+        match index {
+            Some(index) => {
+                self.guards[index] = Some((prefix, guard));
+                Ok(())
+            }
+            None => Err("Too many guards"),
+        }
+    }
+
+    fn guard_for(&self, path: &[u8]) -> Option<Guard<S>> {
+        self.guards.iter().flatten()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_bytes())
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with(b"/") || prefix.ends_with('/'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, guard)| *guard)
+    }
+
+    fn push(&mut self, route: Route<S>) -> Result<(), &'static str> {
+        if self.count >= MAX_ROUTES {
+            return Err("Router is full");
+        }
+
+        self.routes[self.count] = Some(route);
+        self.count += 1;
+        Ok(())
+    }
+
+    // The part handler of the route the request would be dispatched to, if it takes uploads
+    pub fn part_handler(&self, req: &Request, params: &mut RouteParams) -> Option<PartHandler<S>> {
+        for route in self.routes[..self.count].iter().flatten() {
+            if route.method.as_bytes() == req.method.as_bytes() && match_route(route.pattern, req.path.as_bytes(), params) {
+                return route.part_handler;
+            }
+        }
+        None
+    }
+
+    // Runs the guard of the route the request would be dispatched to. Upload routes need this
+    // before their body is streamed, dispatch does it for everything else.
+    pub fn authorize(&self, req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, state: &mut S) -> bool {
+        let mut params = RouteParams::new();
+
+        for route in self.routes[..self.count].iter().flatten() {
+            if route.method.as_bytes() == req.method.as_bytes() && match_route(route.pattern, req.path.as_bytes(), &mut params) {
+                return self.guard_for(req.path.as_bytes()).map_or(true, |guard| guard(req, resp, state));
+            }
+        }
+        true
+    }
 
-                            // println!("Boundary Buffer:\n{}", core::str::from_utf8(&boundary_slice[..boundary_slice.len()]).unwrap_or("<invalid UTF-8>"));
-                        }
+    pub fn dispatch(&self, req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, state: &mut S) {
+        let mut params = RouteParams::new();
+        let mut allow = ByteString::<MAX_HEADER_VALUE>::new(b"");
 
+        for route in self.routes[..self.count].iter().flatten() {
+            if !match_route(route.pattern, req.path.as_bytes(), &mut params) {
+                continue;
+            }
 
+            if route.method.as_bytes() == req.method.as_bytes() {
+                if let Some(guard) = self.guard_for(req.path.as_bytes()) {
+                    if !guard(req, resp, state) {
+                        return;
                     }
-                    _ => {}
                 }
 
+                (route.handler)(req, resp, &params, state);
+                return;
+            }
 
-
+            // Path matched under another method, remember it for the Allow header
+            if allow.len() > 0 {
+                allow.append(b", ");
             }
+            allow.append(route.method.as_bytes());
         }
-    }
 
-    fn parse_post_url_encoded(&mut self, buf: &&[u8]) {
-        let post_content_length = self.headers.data.iter()
-            .find_map(|(key_option, _, values)| {
-                key_option.as_ref().filter(|key| key.as_bytes() == b"Content-Length")
-                    .and_then(|_| values.as_ref())
-                    .map(|value| value.as_bytes())
-            })
-            .and_then(parse_bytes_to_usize)
-            .unwrap_or(0);
+        resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
 
-        if buf.len() >= post_content_length {
-            let post_data = ByteString::new(&buf[buf.len() - post_content_length..]);
-            self.post_param_count = parse_query_string(&post_data, &mut self.post_param_keys, &mut self.post_param_values);
+        if allow.len() > 0 {
+            resp.status = 405;
+            resp.headers.append(ByteString::new(b"Allow"), Some(allow));
         } else {
-            // Handle the error case where the buffer is too short
+            resp.status = 404;
         }
     }
+}
 
-    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
-        get_query_param_value(self.query_param_count, &self.query_param_keys, &self.query_param_values, key)
-    }
+fn match_route(pattern: &'static str, path: &[u8], params: &mut RouteParams) -> bool {
+    params.clear();
 
-    pub fn post(&self, key: &[u8]) -> Option<&[u8]> {
-        get_query_param_value(self.post_param_count, &self.post_param_keys, &self.post_param_values, &key)
+    let mut path_segments = path.split(|&b| b == b'/');
+    let mut consumed = 0;
+
+    for segment in pattern.split('/') {
+        if let Some(name) = segment.strip_prefix('*') {
+            let rest = path.get(consumed..).unwrap_or(&[]);
+            return params.push(name, rest);
+        }
+
+        let path_segment = match path_segments.next() {
+            Some(path_segment) => path_segment,
+            None => return false,
+        };
+        consumed += path_segment.len() + 1;
+
+        if let Some(name) = segment.strip_prefix(':') {
+            if path_segment.is_empty() || !params.push(name, path_segment) {
+                return false;
+            }
+        } else if segment.as_bytes() != path_segment {
+            return false;
+        }
     }
+
+    path_segments.next().is_none()
 }
 
 impl<'a> QueryParam<'a> {
@@ -476,7 +1560,15 @@ impl<'a> QueryParam<'a> {
 fn get_status_message(status_code: usize) -> &'static str {
     match status_code {
         200 => "OK",
+        303 => "See Other",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
         404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
         500 => "Internal Server Error",
         // Add other status codes as needed
         _ => "Unknown",
@@ -491,30 +1583,6 @@ fn trim_quotes(input: &[u8]) -> &[u8] {
     }
 }
 
-fn find_boundary_positions(buf: &[u8], boundary: &[u8]) -> [usize; MAX_BOUNDARY_COUNT] {
-
-    let mut positions = [0; MAX_BOUNDARY_COUNT];
-    let mut count = 0;
-    let mut start = 0;
-
-    while let Some(pos) = buf[start..].windows(boundary.len()).position(|window| window == boundary) {
-        // Adjust the position to the absolute position in the original buffer
-        let absolute_pos = start + pos;
-        positions[count] = absolute_pos;
-        count += 1;
-
-        // Move the start position to the character after the found boundary
-        start = absolute_pos + boundary.len();
-
-        if count >= MAX_BOUNDARY_COUNT {
-            break; // Stop searching after reaching the maximum count
-        }
-    }
-
-    positions
-}
-
-
 pub fn convert_slice_to_fixed_array<const SIZE: usize>(slice: &[u8]) -> [u8; SIZE] {
     let mut array = [0; SIZE];
     let length = slice.len().min(SIZE);
@@ -546,6 +1614,11 @@ pub fn trim_bytes(bytes: &[u8]) -> &[u8] {
 }
 
 pub fn usize_to_bytes(value: usize, buffer: &mut [u8]) -> usize {
+    if value == 0 {
+        buffer[0] = b'0';
+        return 1;
+    }
+
     let mut n = value;
     let mut len = 0;
 
@@ -587,48 +1660,11 @@ pub fn parse_request_line(buf: &[u8]) -> Option<(&[u8], &[u8])> {
     }
 }
 
-pub fn generate_http_response<const N: usize, const M: usize>(
-    status_code: usize,
-    headers: &mut Headers<N, M>,
-    body: &[u8]
-) -> ([u8; BUFFER_SIZE], usize) {
-    let mut response = ByteString::<BUFFER_SIZE>::new(b"HTTP/1.1 ");
-
-    // Append status code and status message
-    let status_message = get_status_message(status_code);
-    let mut status_code_bytes: [u8; 3] = Default::default();
-
-    usize_to_bytes(status_code, &mut status_code_bytes);
-
-    response.append(&status_code_bytes);
-    response.append(b" ");
-    response.append(status_message.as_bytes());
-    response.append(b"\r\n");
-
-    // Convert content length to bytes and create Content-Length header
-    let content_length = body.len();
-    let mut content_length_bytes = [0u8; 10];
-    let content_length_len = usize_to_bytes(content_length, &mut content_length_bytes);
-    let content_length_key = ByteString::<N>::new(b"Content-Length");
-    let content_length_value_bytes = ByteString::<M>::new(&content_length_bytes[..content_length_len]);
-    let content_length_value: HeaderValues<M> = Some(content_length_value_bytes);
-    append_header(&mut headers.data, content_length_key, content_length_value);
-
-    // Append headers
-    match combine_headers(&mut headers.data, &mut response.data, response.length) {
-        Ok(size) => {
-            response.length = size;
-        },
-        Err(_) => return (response.data, 0), // Handle error if headers don't fit
-    };
-
-    // Append blank line and body
-    response.append(b"\r\n");
-    response.append(body);
-
-    (response.data, response.length)
+pub fn parse_request_version(buf: &[u8]) -> Option<&[u8]> {
+    buf.split(|&b| b == b' ').nth(2).filter(|version| version.starts_with(b"HTTP/"))
 }
 
+
 pub fn parse_http_headers<const N: usize, const M: usize>(
     buf: &[u8],
     n: usize,
@@ -686,6 +1722,30 @@ pub fn parse_http_headers<const N: usize, const M: usize>(
 
 
 
+// Every value of a header in a request head, joined with "; " as several Cookie headers are.
+// Values that don't fit are left out whole rather than cut short.
+fn collect_header<const M: usize>(head: &[u8], name: &[u8], out: &mut ByteString<M>) {
+    // Skip the request line, the head ends at the first empty line
+    for line in head.split(|&b| b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(colon_index) = line.iter().position(|&b| b == b':') {
+            if trim_bytes(&line[..colon_index]).eq_ignore_ascii_case(name) {
+                let value = trim_bytes(&line[colon_index + 1..]);
+                let separator: &[u8] = if out.len() == 0 { b"" } else { b"; " };
+
+                if out.len() + separator.len() + value.len() <= M {
+                    out.append(separator);
+                    out.append(value);
+                }
+            }
+        }
+    }
+}
+
 pub fn get_header<const N: usize, const M: usize>(
     headers: [Header<N, M>; MAX_HEADERS],
     header_name: &[u8]
@@ -700,31 +1760,59 @@ pub fn get_header<const N: usize, const M: usize>(
     None
 }
 
-pub fn parse_query_string<'a>(
-    query: &'a ByteString<MAX_QUERY_LENGTH>,
+// Everything after the blank line that ends the header section
+fn request_body(buf: &[u8]) -> &[u8] {
+    match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(index) => &buf[index + 4..],
+        None => &[],
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|window| window == b"\r\n")
+}
+
+// Looks up a header directly in the raw header section, matching the name case-insensitively
+fn find_header_value<'a>(head: &'a [u8], header_name: &[u8]) -> Option<&'a [u8]> {
+    for line in head.split(|&b| b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if let Some(colon_index) = line.iter().position(|&b| b == b':') {
+            if trim_bytes(&line[..colon_index]).eq_ignore_ascii_case(header_name) {
+                return Some(trim_bytes(&line[colon_index + 1..]));
+            }
+        }
+    }
+    None
+}
+
+// Takes query strings and urlencoded bodies alike. Only the first `=` of a pair splits it, so
+// values may hold more, and `name=` or a bare `name` give an empty value.
+pub fn parse_query_string<const L: usize>(
+    query: &ByteString<L>,
     query_param_keys: &mut [ByteString<MAX_QUERY_PARAM_LENGTH>; MAX_QUERY_PARAMS],
     query_param_values: &mut [ByteString<MAX_QUERY_PARAM_LENGTH>; MAX_QUERY_PARAMS],
 ) -> usize {
     let mut param_index = 0;
 
-    let mut pairs: [Option<ByteString<MAX_QUERY_LENGTH>>; MAX_QUERY_PARAMS] = [None; MAX_QUERY_PARAMS];
-    query.split(|&b| b == b'&', &mut pairs);
+    for pair in query.as_bytes().split(|&b| b == b'&').filter(|pair| !pair.is_empty()) {
+        if param_index >= MAX_QUERY_PARAMS {
+            break;
+        }
 
-    for pair_option in pairs.iter() {
-        if let Some(pair) = pair_option {
-            if param_index >= MAX_QUERY_PARAMS {
-                break;
-            }
+        let (key, value) = match pair.iter().position(|&b| b == b'=') {
+            Some(equals) => (&pair[..equals], &pair[equals + 1..]),
+            None => (pair, &b""[..]),
+        };
+        let mut decoded = [0u8; MAX_QUERY_PARAM_LENGTH];
 
-            let mut key_value_pair: [Option<ByteString<MAX_QUERY_LENGTH>>; 2] = [None; 2];
-            pair.split(|&b| b == b'=', key_value_pair.as_mut_slice()); // Adjusted this line
+        let n = url_decode(key, &mut decoded);
+        query_param_keys[param_index] = ByteString::<MAX_QUERY_PARAM_LENGTH>::new(&decoded[..n]);
 
-            if let (Some(key), Some(value)) = (key_value_pair[0].as_ref(), key_value_pair[1].as_ref()) {
-                query_param_keys[param_index] = ByteString::<MAX_QUERY_PARAM_LENGTH>::new(key.as_bytes());
-                query_param_values[param_index] = ByteString::<MAX_QUERY_PARAM_LENGTH>::new(value.as_bytes());
-                param_index += 1;
-            }
-        }
+        let n = url_decode(value, &mut decoded);
+        query_param_values[param_index] = ByteString::<MAX_QUERY_PARAM_LENGTH>::new(&decoded[..n]);
+
+        param_index += 1;
     }
 
     param_index
@@ -791,64 +1879,18 @@ pub fn create_header_values<const N: usize>(values: &[ByteString<N>]) -> HeaderV
     header_value
 }
 
-fn combine_headers<const N: usize, const M: usize>(
-    headers: &[Header<N, M>],
-    buffer: &mut [u8],
-    offset: usize,  // Starting offset in the buffer
-) -> Result<usize, &'static str> {
-    let mut cursor = offset;  // Start from the provided offset
-
-    for &(ref key, _, ref value) in headers.iter() {
-        if let Some(ref key) = key {
-            let key_bytes = key.as_bytes();
-            if cursor + key_bytes.len() + 4 > buffer.len() {
-                return Err("Buffer too small");
-            }
-
-            buffer[cursor..cursor + key_bytes.len()].copy_from_slice(key_bytes);
-            cursor += key_bytes.len();
-
-            if let Some(ref value) = value {
-                let value_bytes = value.as_bytes();
-                if cursor + value_bytes.len() + 2 > buffer.len() {
-                    return Err("Buffer too small");
-                }
-
-                buffer[cursor..cursor + 2].copy_from_slice(b": ");
-                cursor += 2;
 
-                buffer[cursor..cursor + value_bytes.len()].copy_from_slice(value_bytes);
-                cursor += value_bytes.len();
-            }
-
-            buffer[cursor..cursor + 2].copy_from_slice(b"\r\n");
-            cursor += 2;
-        }
-    }
-
-    Ok(cursor) // Return the new offset
-}
-
-pub fn bytes_to_readable_string(bytes: &[u8], output: &mut [u8]) {
-    for (i, &byte) in bytes.iter().enumerate() {
-        if i >= output.len() {
-            break; // Avoid exceeding the buffer size
-        }
 
-        // Check if byte is printable ASCII (space to tilde)
-        if (byte >= 0x20 && byte <= 0x7E) || byte == b'\n' || byte == b'\r' || byte == b'\t' {
-            output[i] = byte;
-        } else {
-            output[i] = b'.'; // Non-printable characters are replaced with a dot
-        }
+// None for anything but digits, for an empty value and for a number usize can't hold
+fn parse_bytes_to_usize(bytes: &[u8]) -> Option<usize> {
+    if bytes.is_empty() {
+        return None;
     }
-}
 
-fn parse_bytes_to_usize(bytes: &[u8]) -> Option<usize> {
-    let mut num = 0;
+    let mut num: usize = 0;
     for &byte in bytes {
         if byte >= b'0' && byte <= b'9' {
-            num = num * 10 + (byte - b'0') as usize;
+            num = num.checked_mul(10)?.checked_add((byte - b'0') as usize)?;
         } else {
             // Return None if the byte slice contains non-digit characters
             return None;
@@ -940,52 +1982,60 @@ mod tests {
 
         let bar_value = get_query_param_value(query_param_count, &query_param_keys, &query_param_values, b"bar");
         assert_eq!(bar_value, Some(&b"22"[..]), "Expected value for 'bar' is 22");
+
+        // Only the first `=` splits a pair, and empty values are kept
+        let query = ByteString::<MAX_QUERY_LENGTH>::new(b"token=abc==&empty=&bare&&x=1");
+        let count = parse_query_string(&query, &mut query_param_keys, &mut query_param_values);
+        assert_eq!(count, 4);
+        assert_eq!(get_query_param_value(count, &query_param_keys, &query_param_values, b"token"), Some(&b"abc=="[..]));
+        assert_eq!(get_query_param_value(count, &query_param_keys, &query_param_values, b"empty"), Some(&b""[..]));
+        assert_eq!(get_query_param_value(count, &query_param_keys, &query_param_values, b"bare"), Some(&b""[..]));
+        assert_eq!(get_query_param_value(count, &query_param_keys, &query_param_values, b"x"), Some(&b"1"[..]));
     }
 
     #[test]
-    fn test_response() {
-        let mut content_length_bytes = [0u8; 10];
+    fn test_request_params_are_url_decoded() {
+        let buf = b"POST /sign-up?filename=my%20file+1.txt HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 35\r\n\r\nusername=a+b&password=caf%C3%A9%26x";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
 
-        let mut headers = Headers::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
-        headers.append(ByteString::new(b"Content-Type"),  Some(ByteString::new(b"text/html")));
-
-        let (http_response, mut response_length) =
-            generate_http_response(
-                200,
-                &mut headers,
-                b"hello"
-            );
-
-        // Convert to readable format for debugging
-        let mut readable_response = [0u8; BUFFER_SIZE];
-        bytes_to_readable_string(&http_response[..response_length], &mut readable_response);
-        let mut readable_expected = [0u8; BUFFER_SIZE];
-        let expected_response: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\nhello";
-        bytes_to_readable_string(expected_response, &mut readable_expected);
-
-        // Print or log for debugging
-        println!("Actual: {}", core::str::from_utf8(&readable_response[..response_length]).unwrap_or("<invalid UTF-8>"));
-        println!("Expected: {}", core::str::from_utf8(&readable_expected[..expected_response.len()]).unwrap_or("<invalid UTF-8>"));
-
-        assert_eq!(&http_response[..response_length], expected_response, "Response did not match expected value.");
-
-        // assert_eq!(http_response[..response_length],
-        //            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\nhello"[..],
-        //            "Expected value HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\nhello"
-        // );
-
-        let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-
-        match combine_headers(&headers.data, &mut buffer, 0) {
-            Ok(size) => {
-                response_length += size;
-            }
-            Err(_) => {
-                // Buffer was too small, handle the error
-            }
-        }
+        assert_eq!(req.get(b"filename"), Some(&b"my file 1.txt"[..]));
+        assert_eq!(req.post(b"username"), Some(&b"a b"[..]));
+        assert_eq!(req.post(b"password"), Some("café&x".as_bytes()));
+    }
+
+    #[test]
+    fn test_response() {
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        resp.status = 200;
+        resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+        resp.set_cookie(&Cookie::new(b"name", b"value"));
+        resp.write(b"hello");
+
+        let mut output = [0u8; 256];
+        let mut sink: &mut [u8] = &mut output;
+        embassy_futures::block_on(resp.send(&mut sink)).unwrap();
+
+        let remaining = sink.len();
+        let expected: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nSet-Cookie: name=value\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(&output[..output.len() - remaining], expected);
     }
 
+    #[test]
+    fn test_embed_file() {
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        resp.write(b"<textarea>");
+        resp.embed_file(b"notes.txt", Escape::Html);
+        resp.write(b"</textarea>");
+
+        let file = resp.file.unwrap();
+        assert_eq!(file.path.as_bytes(), b"notes.txt");
+        assert_eq!(&resp.body.as_bytes()[..file.at], b"<textarea>");
+        assert_eq!(file.escape, Escape::Html);
+
+        resp.send_file(b"page.html");
+        assert_eq!(resp.file.unwrap().escape, Escape::Raw);
+    }
 
     #[test]
     fn test_request() {
@@ -993,7 +2043,7 @@ mod tests {
 
         let mut req = Request::new();
 
-        req.parse(buf, 421);
+        assert_eq!(req.parse(buf, 421), Ok(()));
 
 
         assert_eq!(&req.method.as_bytes(), b"GET", "Method not GET");
@@ -1005,7 +2055,7 @@ mod tests {
 
         let mut req = Request::new();
 
-        req.parse(buf, 138);
+        assert_eq!(req.parse(buf, 138), Ok(()));
 
         let field1 = req.post(b"field1");
         let field2 = req.post(b"field2");
@@ -1021,13 +2071,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_http_post_long_body() {
+        // Longer than a query string may be, and the header names written in lower case
+        let mut body = [b'x'; 3000];
+        body[..2].copy_from_slice(b"a=");
+        body[2990..].copy_from_slice(b"&last=end!");
+        let head = b"POST /test HTTP/1.1\r\ncontent-type: application/x-www-form-urlencoded\r\ncontent-length: 3000\r\n\r\n";
+
+        let mut buf = [0u8; 3200];
+        buf[..head.len()].copy_from_slice(head);
+        buf[head.len()..head.len() + body.len()].copy_from_slice(&body);
+        let length = head.len() + body.len();
+
+        let mut req = Request::new();
+        assert_eq!(req.parse(&buf[..length], length), Ok(()));
+        assert_eq!(req.post(b"last"), Some(&b"end!"[..]));
+
+        // Announcing more than MAX_POST_LENGTH is turned away
+        let head = b"POST /test HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\ncontent-length: 5000\r\n\r\na=1";
+        let mut req = Request::new();
+        assert_eq!(req.parse(head, head.len()), Err(ParseError::BodyTooLarge));
+    }
+
     #[test]
     fn test_http_post_multipart() {
         let buf = b"POST /api/upload HTTP/1.1\r\nContent-Length: 242\r\nContent-Type: multipart/form-data; boundary=PieBoundary123456789012345678901234567\r\nHost: localhost:8000\r\nUser-Agent: HTTPie\r\n\r\n--PieBoundary123456789012345678901234567\r\nContent-Disposition: form-data; name=\"field1\"\r\n\r\nvalue1\r\n--PieBoundary123456789012345678901234567\r\nContent-Disposition: form-data; name=\"field2\"\r\n\r\nvalue2\r\n--PieBoundary123456789012345678901234567--\r\n";
 
         let mut req = Request::new();
 
-        req.parse(buf, 255);
+        assert_eq!(req.parse(buf, 255), Ok(()));
 
         let field1 = req.post(b"field1");
         let field2 = req.post(b"field2");
@@ -1048,4 +2121,538 @@ mod tests {
             None => panic!("req.post(b\"field2\") is None"),
         }
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_request_parser_split_reads() {
+        let buf = b"POST /test HTTP/1.1\r\nHost: foo.example\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 27\r\n\r\nfield1=value1&field2=value2";
+
+        let mut parser = RequestParser::new();
+
+        // Split inside the request line, between the CR and LF and inside the body
+        assert_eq!(parser.feed(&buf[..7]), ParseStatus::Incomplete);
+        assert_eq!(parser.phase(), ParsePhase::RequestLine);
+        assert_eq!(parser.feed(&buf[7..39]), ParseStatus::Incomplete);
+        assert_eq!(parser.phase(), ParsePhase::Headers);
+        assert_eq!(parser.feed(&buf[39..115]), ParseStatus::Incomplete);
+        assert_eq!(parser.phase(), ParsePhase::Body);
+        assert_eq!(parser.feed(&buf[115..]), ParseStatus::Complete);
+
+        let mut req = Request::new();
+        assert_eq!(parser.parse(&mut req), Ok(()));
+
+        assert_eq!(req.method.as_bytes(), b"POST");
+        assert_eq!(req.post(b"field1"), Some(&b"value1"[..]));
+        assert_eq!(req.post(b"field2"), Some(&b"value2"[..]));
+    }
+
+    #[test]
+    fn test_request_parser_overflow() {
+        let buf = b"POST /test HTTP/1.1\r\nContent-Length: 999999\r\n\r\n";
+
+        let mut parser = RequestParser::new();
+
+        assert_eq!(parser.feed(buf), ParseStatus::Error(ParseError::BodyTooLarge));
+
+        // More digits than usize holds, and a length that would wrap once the head is added
+        let buf = b"POST /test HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n";
+        assert_eq!(RequestParser::new().feed(buf), ParseStatus::Error(ParseError::BodyTooLarge));
+        let buf = b"POST /test HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n";
+        assert_eq!(RequestParser::new().feed(buf), ParseStatus::Error(ParseError::BodyTooLarge));
+
+        let buf = b"POST /test HTTP/1.1\r\nContent-Length: \r\n\r\n";
+        assert_eq!(RequestParser::new().feed(buf), ParseStatus::Error(ParseError::MalformedHeader));
+    }
+    fn route_test_user(_req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, params: &RouteParams, hits: &mut usize) {
+        resp.status = 200;
+        resp.write(params.get("id").unwrap_or(b""));
+        *hits += 1;
+    }
+
+    fn route_test_files(_req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, params: &RouteParams, hits: &mut usize) {
+        resp.status = 200;
+        resp.write(params.get("path").unwrap_or(b""));
+        *hits += 1;
+    }
+
+    #[test]
+    fn test_router() {
+        let mut router = Router::<usize>::new();
+        router.get("/users/:id", route_test_user).unwrap();
+        router.post("/users/:id", route_test_user).unwrap();
+        router.get("/files/*path", route_test_files).unwrap();
+
+        let mut hits = 0;
+
+        let mut req = Request::new();
+        let buf = b"GET /users/42?x=1 HTTP/1.1\r\n\r\n";
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body.as_bytes(), b"42");
+
+        let mut req = Request::new();
+        let buf = b"GET /files/a/b.txt HTTP/1.1\r\n\r\n";
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body.as_bytes(), b"a/b.txt");
+        assert_eq!(hits, 2);
+
+        let mut req = Request::new();
+        let buf = b"GET /users HTTP/1.1\r\n\r\n";
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 404);
+
+        let mut req = Request::new();
+        let buf = b"DELETE /users/42 HTTP/1.1\r\n\r\n";
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 405);
+        assert_eq!(get_header(resp.headers.data, b"Allow").flatten().unwrap().as_bytes(), b"GET, POST");
+        assert_eq!(hits, 2);
+    }
+    #[test]
+    fn test_response_writer() {
+        let mut headers = Headers::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+        let mut output = [0u8; 256];
+        let mut sink: &mut [u8] = &mut output;
+
+        embassy_futures::block_on(async {
+            let mut writer = ResponseWriter::start(&mut sink, 200, &headers, &[], BodyLength::Fixed(11)).await.unwrap();
+            writer.write_body(b"hello").await.unwrap();
+            writer.write_body(b" world").await.unwrap();
+            writer.finish().await.unwrap();
+        });
+
+        let remaining = sink.len();
+        let expected: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 11\r\n\r\nhello world";
+        assert_eq!(&output[..output.len() - remaining], expected);
+    }
+
+    #[test]
+    fn test_response_writer_chunked() {
+        let headers = Headers::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+
+        let mut output = [0u8; 256];
+        let mut sink: &mut [u8] = &mut output;
+
+        embassy_futures::block_on(async {
+            let mut writer = ResponseWriter::start(&mut sink, 200, &headers, &[], BodyLength::Chunked).await.unwrap();
+            writer.write_body(b"hello").await.unwrap();
+            writer.write_body(&[b'x'; 26]).await.unwrap();
+            writer.finish().await.unwrap();
+        });
+
+        let remaining = sink.len();
+        let expected: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n1a\r\nxxxxxxxxxxxxxxxxxxxxxxxxxx\r\n0\r\n\r\n";
+        assert_eq!(&output[..output.len() - remaining], expected);
+    }
+    #[test]
+    fn test_chunked_decoder() {
+        let body = b"5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nExpires: never\r\n\r\n";
+
+        let mut decoder = ChunkedDecoder::new();
+        let mut decoded = ByteString::<64>::new(b"");
+
+        for &byte in body.iter() {
+            if let Some(data) = decoder.push(byte).unwrap() {
+                decoded.append(&[data]);
+            }
+        }
+
+        assert!(decoder.is_done());
+        assert_eq!(decoded.as_bytes(), b"hello world");
+        assert_eq!(decoder.trailers(), b"Expires: never\r\n");
+    }
+
+    #[test]
+    fn test_chunked_decoder_rejects_bad_framing() {
+        let cases: [(&[u8], ChunkedError); 6] = [
+            (b"\r\n", ChunkedError::InvalidSizeLine),
+            (b"x\r\n", ChunkedError::InvalidSizeLine),
+            (b"5 \r\n", ChunkedError::InvalidSizeLine),
+            (b"5\n", ChunkedError::InvalidSizeLine),
+            (b"123456789\r\n", ChunkedError::InvalidSizeLine),
+            (b"2\r\nabc\r\n", ChunkedError::InvalidChunkEnd),
+        ];
+
+        for (body, expected) in cases.iter() {
+            let mut decoder = ChunkedDecoder::new();
+            let result = body.iter().try_for_each(|&byte| decoder.push(byte).map(|_| ()));
+            assert_eq!(result, Err(*expected), "Unexpected result for {:?}", body);
+        }
+    }
+
+    #[test]
+    fn test_request_parser_chunked() {
+        let buf = b"POST /test HTTP/1.1\r\nHost: foo.example\r\nContent-Type: application/x-www-form-urlencoded\r\nTransfer-Encoding: chunked\r\n\r\nd\r\nfield1=value1\r\ne\r\n&field2=value2\r\n0\r\nX-Checksum: abc\r\n\r\n";
+
+        let mut parser = RequestParser::new();
+
+        // Feed the request a few bytes at a time so chunk framing is split across reads
+        let mut status = ParseStatus::Incomplete;
+        for chunk in buf.chunks(7) {
+            status = parser.feed(chunk);
+        }
+        assert_eq!(status, ParseStatus::Complete);
+
+        let mut req = Request::new();
+        assert_eq!(parser.parse(&mut req), Ok(()));
+
+        assert_eq!(req.post(b"field1"), Some(&b"value1"[..]));
+        assert_eq!(req.post(b"field2"), Some(&b"value2"[..]));
+        assert_eq!(get_header(req.headers.data, b"X-Checksum").flatten().unwrap().as_bytes(), b"abc");
+    }
+
+    #[test]
+    fn test_request_parser_unsupported_transfer_encoding() {
+        let buf = b"POST /test HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+
+        let mut parser = RequestParser::new();
+
+        assert_eq!(parser.feed(buf), ParseStatus::Error(ParseError::MalformedHeader));
+    }
+
+    #[test]
+    fn test_request_parser_errors() {
+        let mut parser = RequestParser::new();
+        assert_eq!(parser.feed(b"GARBAGE\r\n\r\n"), ParseStatus::Error(ParseError::BadRequestLine));
+
+        let mut parser = RequestParser::new();
+        assert_eq!(parser.feed(b"GET / HTTP/1.1\r\nNo colon here\r\n\r\n"), ParseStatus::Error(ParseError::MalformedHeader));
+
+        let mut parser = RequestParser::new();
+        parser.feed(b"GET / HTTP/1.1\r\n");
+        for _ in 0..MAX_HEADERS {
+            parser.feed(b"X-Test: 1\r\n");
+        }
+        assert_eq!(parser.feed(b"\r\n"), ParseStatus::Error(ParseError::TooManyHeaders));
+
+        let mut parser = RequestParser::new();
+        parser.feed(b"GET / HTTP/1.1\r\nX-Test: ");
+        assert_eq!(parser.feed(&[b'a'; MAX_HEADER_LINE_LENGTH]), ParseStatus::Error(ParseError::HeaderTooLong));
+
+        assert_eq!(ParseError::HeaderTooLong.status(), 431);
+        assert_eq!(ParseError::BodyTooLarge.status(), 413);
+        assert_eq!(ParseError::MalformedMultipart.status(), 400);
+    }
+
+    #[test]
+    fn test_request_parse_malformed_multipart() {
+        // Binary content is fine, it is never treated as text
+        let buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\n\r\n--xyz\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\n\xff\xfe\x00\x01\r\n--xyz--\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        assert_eq!(req.post(b"file"), Some(&b"\xff\xfe\x00\x01"[..]));
+
+        let buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data\r\n\r\n--xyz\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Err(ParseError::MalformedMultipart));
+
+        let buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\n\r\n--xyz\r\n--xyz--";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Err(ParseError::MalformedMultipart));
+
+        let buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\n\r\n--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nno end";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Err(ParseError::MalformedMultipart));
+    }
+
+    #[test]
+    fn test_multipart_reader() {
+        let body = b"preamble\r\n--xyz\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nhello\r\n--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a b.txt\"\r\nContent-Type: text/plain\r\n\r\nline\r\n--xy\r\n-almost\r\n--xyz--\r\nepilogue";
+
+        // Byte by byte, so every boundary and header is split across feeds
+        let mut reader = MultipartReader::new(b"xyz").unwrap();
+        let mut names = ByteString::<64>::new(b"");
+        let mut file = ByteString::<64>::new(b"");
+        let mut ends = 0;
+
+        for byte in body.iter() {
+            reader.feed(core::slice::from_ref(byte), &mut |event| match event {
+                MultipartEvent::Start(part) => {
+                    names.append(part.name.as_bytes());
+                    names.append(b",");
+                    if let Some(filename) = &part.filename {
+                        assert_eq!(filename.as_bytes(), b"a b.txt");
+                        assert_eq!(part.content_type.as_bytes(), b"text/plain");
+                    }
+                }
+                MultipartEvent::Data(part, data) => {
+                    if part.filename.is_some() {
+                        file.append(data);
+                    }
+                }
+                MultipartEvent::End(_) => ends += 1,
+            }).unwrap();
+        }
+
+        assert!(reader.is_done());
+        assert_eq!(reader.finish(), Ok(()));
+        assert_eq!(names.as_bytes(), b"title,file,");
+        assert_eq!(file.as_bytes(), b"line\r\n--xy\r\n-almost");
+        assert_eq!(ends, 2);
+
+        // Data larger than a chunk arrives in several pieces
+        let mut reader = MultipartReader::new(b"xyz").unwrap();
+        let mut data_events = 0;
+        let mut data_length = 0;
+        reader.feed(b"--xyz\r\nContent-Disposition: form-data; name=\"big\"\r\n\r\n", &mut |_| {}).unwrap();
+        reader.feed(&[b'a'; MULTIPART_CHUNK_SIZE * 2 + 10], &mut |event| {
+            if let MultipartEvent::Data(_, data) = event {
+                data_events += 1;
+                data_length += data.len();
+            }
+        }).unwrap();
+        assert_eq!(data_events, 3);
+        assert_eq!(data_length, MULTIPART_CHUNK_SIZE * 2 + 10);
+        assert_eq!(reader.finish(), Err(ParseError::MalformedMultipart));
+    }
+
+    #[test]
+    fn test_request_parser_stream_multipart() {
+        let head = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\nContent-Length: 20000\r\n\r\n--xyz\r\n";
+
+        let mut parser = RequestParser::new();
+        parser.stream_multipart(true);
+        assert_eq!(parser.feed(head), ParseStatus::HeadComplete);
+        assert_eq!(parser.content_length(), 20000);
+        assert_eq!(parser.body(), b"--xyz\r\n");
+
+        let mut req = Request::new();
+        assert_eq!(parser.parse_head(&mut req), Ok(()));
+        assert_eq!(req.path.as_bytes(), b"/upload");
+        assert_eq!(req.multipart_boundary(), Some(&b"xyz"[..]));
+
+        // Too big to buffer when the route doesn't stream it
+        assert_eq!(parser.buffer_body(), ParseStatus::Error(ParseError::BodyTooLarge));
+
+        // A length reaching past the end of memory still only shows what came in
+        let huge = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\nContent-Length: 18446744073709551615\r\n\r\n--xyz";
+        let mut parser = RequestParser::new();
+        parser.stream_multipart(true);
+        assert_eq!(parser.feed(huge), ParseStatus::HeadComplete);
+        assert_eq!(parser.body(), b"--xyz");
+        assert_eq!(parser.buffer_body(), ParseStatus::Error(ParseError::BodyTooLarge));
+
+        // Without streaming the parser behaves as before
+        let mut parser = RequestParser::new();
+        assert_eq!(parser.feed(head), ParseStatus::Error(ParseError::BodyTooLarge));
+    }
+
+    fn route_test_upload_part(_req: &Request, _resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, _params: &RouteParams, _event: &MultipartEvent, hits: &mut usize) -> Result<(), &'static str> {
+        *hits += 1;
+        Ok(())
+    }
+
+    fn guard_test_key(req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, _hits: &mut usize) -> bool {
+        if req.headers.get(b"X-Key") == Some(&b"secret"[..]) {
+            return true;
+        }
+        resp.status = 403;
+        false
+    }
+
+    #[test]
+    fn test_router_guard() {
+        let mut router = Router::<usize>::new();
+        router.get("/users/:id", route_test_user).unwrap();
+        router.get("/files", route_test_user).unwrap();
+        router.get("/files/*path", route_test_files).unwrap();
+        router.upload("/files/*path", route_test_upload_part, route_test_files).unwrap();
+
+        assert_eq!(router.guard("/files", guard_test_key), Ok(()));
+        // Routes registered after the guard are just as covered
+        router.get("/filesystem", route_test_user).unwrap();
+        router.get("/files/new/:name", route_test_user).unwrap();
+
+        let mut hits = 0;
+
+        let buf = b"GET /files/a.txt HTTP/1.1\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 403);
+        assert_eq!(hits, 0);
+
+        let buf = b"GET /files/a.txt HTTP/1.1\r\nX-Key: secret\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 200);
+        assert_eq!(hits, 1);
+
+        // Sharing the prefix isn't enough, the path has to be below it
+        let buf = b"GET /filesystem HTTP/1.1\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 200);
+        assert_eq!(hits, 2);
+
+        let buf = b"POST /files/b.txt HTTP/1.1\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        assert!(!router.authorize(&req, &mut resp, &mut hits));
+        assert_eq!(resp.status, 403);
+
+        let buf = b"GET /users/1 HTTP/1.1\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        assert!(router.authorize(&req, &mut resp, &mut hits));
+
+        let buf = b"GET /files/new/c.txt HTTP/1.1\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 403);
+        assert_eq!(hits, 2);
+
+        // The longest prefix decides
+        router.guard("/files/new", guard_test_open).unwrap();
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        router.dispatch(&req, &mut resp, &mut hits);
+        assert_eq!(resp.status, 200);
+        assert_eq!(hits, 3);
+    }
+
+    fn guard_test_open(_req: &Request, _resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, _hits: &mut usize) -> bool {
+        true
+    }
+
+    #[test]
+    fn test_router_part_handler() {
+        let mut router = Router::<usize>::new();
+        router.post("/sign-up", route_test_user).unwrap();
+        router.upload("/upload/:dir", route_test_upload_part, route_test_user).unwrap();
+
+        let mut params = RouteParams::new();
+        let mut req = Request::new();
+
+        let buf = b"POST /upload/docs HTTP/1.1\r\n\r\n";
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        assert!(router.part_handler(&req, &mut params).is_some());
+        assert_eq!(params.get("dir"), Some(&b"docs"[..]));
+
+        let buf = b"POST /sign-up HTTP/1.1\r\n\r\n";
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+        assert!(router.part_handler(&req, &mut params).is_none());
+    }
+
+    #[test]
+    fn test_response_writer_trailers() {
+        let headers = Headers::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        let mut trailers = Headers::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        trailers.append(ByteString::new(b"X-Checksum"), Some(ByteString::new(b"abc")));
+
+        let mut output = [0u8; 256];
+        let mut sink: &mut [u8] = &mut output;
+
+        embassy_futures::block_on(async {
+            let mut writer = ResponseWriter::start(&mut sink, 200, &headers, &[], BodyLength::Chunked).await.unwrap();
+            writer.write_body(b"hello").await.unwrap();
+            writer.finish_with_trailers(&trailers).await.unwrap();
+        });
+
+        let remaining = sink.len();
+        let expected: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nX-Checksum: abc\r\n\r\n";
+        assert_eq!(&output[..output.len() - remaining], expected);
+    }
+    #[test]
+    fn test_request_parser_pipelined() {
+        let buf = b"GET /one HTTP/1.1\r\nHost: foo.example\r\n\r\nPOST /two HTTP/1.1\r\nContent-Length: 3\r\n\r\na=1GET /three HTTP/1.1\r\n";
+
+        let mut parser = RequestParser::new();
+        assert_eq!(parser.feed(buf), ParseStatus::Complete);
+
+        let mut req = Request::new();
+        assert_eq!(parser.parse(&mut req), Ok(()));
+        assert_eq!(req.path.as_bytes(), b"/one");
+
+        assert_eq!(parser.next_request(), ParseStatus::Complete);
+        let mut req = Request::new();
+        assert_eq!(parser.parse(&mut req), Ok(()));
+        assert_eq!(req.path.as_bytes(), b"/two");
+        assert_eq!(req.post(b"a"), None);
+
+        // The third request has only partly arrived
+        assert_eq!(parser.next_request(), ParseStatus::Incomplete);
+        assert!(!parser.is_empty());
+        assert_eq!(parser.feed(b"\r\n"), ParseStatus::Complete);
+        let mut req = Request::new();
+        assert_eq!(parser.parse(&mut req), Ok(()));
+        assert_eq!(req.path.as_bytes(), b"/three");
+
+        assert_eq!(parser.next_request(), ParseStatus::Incomplete);
+        assert!(parser.is_empty());
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let cases: [(&[u8], bool); 5] = [
+            (b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", true),
+            (b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", true),
+            (b"GET / HTTP/1.1\r\nConnection: upgrade, close\r\n\r\n", false),
+        ];
+
+        for (buf, expected) in cases.iter() {
+            let mut req = Request::new();
+            assert_eq!(req.parse(buf, buf.len()), Ok(()));
+            assert_eq!(req.keep_alive(), *expected, "Unexpected keep-alive for {:?}", buf);
+        }
+    }
+
+    #[test]
+    fn test_cookies() {
+        let buf = b"GET / HTTP/1.1\r\nCookie: session=abc; theme=dark\r\nCookie: flash=saved\r\n\r\n";
+        let mut req = Request::new();
+        assert_eq!(req.parse(buf, buf.len()), Ok(()));
+
+        assert_eq!(req.cookie(b"session"), Some(&b"abc"[..]));
+        assert_eq!(req.cookie(b"flash"), Some(&b"saved"[..]));
+        assert_eq!(req.cookie(b"missing"), None);
+        assert_eq!(req.cookies().count(), 3);
+
+        // A login token is longer than other header values get to be
+        let token = [b'a'; 400];
+        let mut long = ByteString::<1024>::new(b"GET / HTTP/1.1\r\nCookie: token=");
+        long.append(&token);
+        long.append(b"\r\nAuthorization: Bearer ");
+        long.append(&token);
+        long.append(b"\r\n\r\n");
+        let mut req = Request::new();
+        assert_eq!(req.parse(long.as_bytes(), long.len()), Ok(()));
+        assert_eq!(req.cookie(b"token"), Some(&token[..]));
+        assert_eq!(req.authorization().map(|value| value.len()), Some(7 + token.len()));
+
+        let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+        resp.status = 200;
+        resp.set_cookie(&Cookie::new(b"session", b"abc").path(b"/").http_only());
+        resp.set_cookie(&Cookie::removal(b"flash").path(b"/"));
+
+        let mut output = [0u8; 512];
+        let mut sink = &mut output[..];
+        embassy_futures::block_on(resp.send(&mut sink)).unwrap();
+        let written = 512 - sink.len();
+        let output = &output[..written];
+
+        let set_cookies = output.windows(12).filter(|window| window == b"Set-Cookie: ").count();
+        assert_eq!(set_cookies, 2);
+        assert_eq!(resp.set_cookies().count(), 2);
+        let expected = b"Set-Cookie: session=abc; Path=/; HttpOnly\r\n";
+        assert!(output.windows(expected.len()).any(|window| window == expected));
+    }
+}
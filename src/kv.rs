@@ -92,6 +92,65 @@ impl<K, V> KeyValueStore<K, V>
         }
         None
     }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        for item in self.items.iter_mut() {
+            if let Some(KeyValuePair { key: ref k, value: ref mut v }) = item {
+                if k == key {
+                    return Some(v);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        for item in self.items.iter_mut() {
+            if matches!(item, Some(KeyValuePair { key: k, .. }) if *k == *key) {
+                self.count -= 1;
+                return item.take().map(|pair| pair.value);
+            }
+        }
+        None
+    }
+
+    // Drops every item the predicate returns false for
+    pub fn retain<F>(&mut self, mut keep: F)
+        where
+            F: FnMut(&K, &V) -> bool,
+    {
+        for item in self.items.iter_mut() {
+            if let Some(KeyValuePair { key, value }) = item {
+                if !keep(key, value) {
+                    *item = None;
+                    self.count -= 1;
+                }
+            }
+        }
+    }
+
+    // The first value the predicate accepts
+    pub fn find<F>(&self, mut matches: F) -> Option<&V>
+        where
+            F: FnMut(&K, &V) -> bool,
+    {
+        for item in self.items.iter() {
+            if let Some(KeyValuePair { key, value }) = item {
+                if matches(key, value) {
+                    return Some(value);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count >= MAX_ITEMS
+    }
 }
 
 impl Serializable for u16 {
@@ -172,4 +231,29 @@ mod tests {
         // Deserialize
         let loaded_user = UserDummy::deserialize(bytes);
     }
+
+    #[test]
+    fn test_kv_remove() {
+        let mut store = KeyValueStore::<u16, u16>::new();
+        store.add(1, 10).unwrap();
+        store.add(2, 20).unwrap();
+        store.add(3, 30).unwrap();
+
+        assert_eq!(store.remove(&2), Some(20));
+        assert_eq!(store.remove(&2), None);
+        assert_eq!(store.len(), 2);
+
+        *store.get_mut(&3).unwrap() += 1;
+        store.retain(|_, value| *value > 10);
+        assert_eq!(store.get(&1), None);
+        assert_eq!(store.get(&3), Some(&31));
+        assert_eq!(store.len(), 1);
+
+        // Freed slots are reused
+        store.add(4, 40).unwrap();
+        assert_eq!(store.get(&4), Some(&40));
+
+        assert_eq!(store.find(|_, value| *value > 35), Some(&40));
+        assert_eq!(store.find(|key, _| *key == 1), None);
+    }
 }
\ No newline at end of file
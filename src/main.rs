@@ -6,34 +6,38 @@
 
 use embedded_sdmmc::SdCardError;
 use core::fmt::Debug;
-use crate::http::{BUFFER_SIZE, ByteString, get_query_param_value, MAX_HEADER_KEY, MAX_HEADER_VALUE, Request, Response};
+use crate::http::{BodyLength, ByteString, FileBody, MAX_HEADER_KEY, MAX_HEADER_VALUE, MultipartReader, ParseError, ParseStatus, PartHandler, Request, RequestParser, Response, ResponseWriter, RouteParams, Router};
 use core::str::from_utf8;
+use cyw43::Control;
 use cyw43_pio::PioSpi;
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_net::tcp::TcpSocket;
-use embassy_net::{Config, Stack, StackResources};
+use embassy_net::{Config, IpAddress, Stack, StackResources};
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::{Level, Output, Pin};
 use embassy_rp::peripherals::{DMA_CH0, PIN_23, PIN_25, PIO0, SPI1};
 use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_rp::spi::{Spi, Config as Spi_Config};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
 use embedded_sdmmc::{Directory, Error as Sdmmc_Error, File, SdCard, TimeSource, Timestamp, Volume, VolumeIdx, VolumeManager};
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Timer};
 use embedded_io_async::Write;
 use static_cell::make_static;
 use crate::kv::{KeyValueStore, Serializable};
-use crate::user::User;
+use crate::user::{PasswordThrottle, Role, User};
 use {defmt_rtt as _, panic_probe as _};
-use crate::routes::sign_up::post::route_sign_up_post;
+use crate::routes::{AppState, Page};
+use crate::template::{write_escaped, Escape};
+use crate::session::SessionStore;
+use crate::entropy::Csprng;
+use rand::RngCore;
 use embedded_hal::blocking::delay::DelayUs;
 use core::fmt::Write as CoreWrite;
 use critical_section::CriticalSection;
 use critical_section::with;
-use crate::base64::base64_url_encode;
-use crate::jwt::generate_keys;
-use crate::sdcard::{CALLBACK, Delayer, MyTimeSource, read_file_async, ReadCallback, SDCARD_MANAGER, SdCardManager, SdCardError as SdError, read_file, list_directory, FileInfo};
-use crate::template::replace;
+use crate::sdcard::{CALLBACK, Delayer, MyTimeSource, read_file_async, ReadCallback, SDCARD_MANAGER, SdCardManager, SdCardError as SdError, SdVolumeManager, open_file, read_chunk, close_file, file_length};
 
 
 mod http;
@@ -43,7 +47,14 @@ mod template;
 mod routes;
 mod sdcard;
 mod jwt;
+mod claims;
 mod base64;
+mod url;
+mod cookie;
+mod hmac;
+mod session;
+mod entropy;
+mod csrf;
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
@@ -52,6 +63,23 @@ bind_interrupts!(struct Irqs {
 const WIFI_NETWORK: &str = "WIFI_SSID_HERE";
 const WIFI_PASSWORD: &str = "WIFI_PASSWORD_HERE";
 
+// (issuer, shared secret) of clients that sign their tokens with HS256, secrets are 32 to 64 bytes.
+// Their tokens name the issuer as the kid and never log anyone in; /me answers them with their
+// claims, see auth::authenticate_client.
+const HS256_CLIENTS: &[(&[u8], &[u8])] = &[];
+
+// (username, password) of the admin account made at boot, sign-ups only ever get the user role.
+// Users live in RAM, so this is the only way to have an admin after a reboot.
+const ADMIN_ACCOUNT: Option<(&[u8], &[u8])> = None;
+
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEP_ALIVE_HEADER: &[u8] = b"timeout=5, max=100";
+const MAX_KEEP_ALIVE_REQUESTS: usize = 100;
+
+const SERVER_POOL_SIZE: usize = 3;
+// One socket per server task plus one for DHCP
+const SOCKET_COUNT: usize = SERVER_POOL_SIZE + 1;
+
 
 #[embassy_executor::task]
 async fn wifi_task(
@@ -125,7 +153,7 @@ async fn main(spawner: Spawner) {
     }
 
     // println!("Card size {} bytes", ?);
-    let mut volume_mgr = VolumeManager::new(sdcard, time_source);
+    let mut volume_mgr = SdVolumeManager::new(VolumeManager::new(sdcard, time_source));
 
     //
     // with(|cs| {
@@ -146,22 +174,69 @@ async fn main(spawner: Spawner) {
 
     let config = Config::dhcpv4(Default::default());
 
-    // Generate random seed
-    let seed = 0x0123_4567_89ab_cdef; // chosen by fair dice roll. guarenteed to be random.
+    // Everything random from here on, starting with the network stack's seed, comes from the CSPRNG
+    entropy::init();
+    let seed = Csprng.next_u64();
 
     // Init network stack
     let stack = &*make_static!(Stack::new(
         net_device,
         config,
-        make_static!(StackResources::<2>::new()),
+        make_static!(StackResources::<SOCKET_COUNT>::new()),
         seed
     ));
 
     unwrap!(spawner.spawn(net_task(stack)));
 
 
+    // Sessions only live in RAM, so a fresh signing key on every boot is all they need
+    let mut session_key = [0u8; 32];
+    Csprng.fill_bytes(&mut session_key);
+
+    // Login tokens have to outlive a reboot, so their keys are kept on the card
+    let mut jwt_keys = match jwt::KeyManager::load_or_create(&mut volume_mgr) {
+        Ok(keys) => keys,
+        Err(err) => {
+            // The key file isn't touched, a key made now only lives until the next reboot
+            warn!("JWT keys not loaded: {}, tokens won't survive a reboot", err);
+            // Without any key nothing could be signed, so there's no point carrying on
+            jwt::KeyManager::new(unwrap!(jwt::SigningKey::generate()))
+        }
+    };
+    for (issuer, secret) in HS256_CLIENTS {
+        if let Err(err) = jwt_keys.add_hmac_issuer(issuer, secret) {
+            warn!("HS256 client not registered: {}", err);
+        }
+    }
+
     let mut id_store = KeyValueStore::<u16, u16>::new();
     let mut user_store = KeyValueStore::<u16, User>::new();
+    if let Some((username, password)) = ADMIN_ACCOUNT {
+        let mut admin = User::new();
+        admin.id = 1;
+        admin.username[..username.len().min(32)].copy_from_slice(&username[..username.len().min(32)]);
+        admin.set_password(&mut Csprng, password).await;
+        admin.role = Role::Admin;
+        unwrap!(user_store.add(admin.id, admin));
+        unwrap!(id_store.set(0, 2));
+    }
+
+    let app_state = &*make_static!(Mutex::<CriticalSectionRawMutex, AppState>::new(AppState {
+        id_store,
+        user_store,
+        user_epoch: Csprng.next_u32(),
+        sessions: SessionStore::new(session_key),
+        password_throttle: PasswordThrottle::new(),
+        jwt_keys,
+        volume_mgr,
+        control_action: None,
+        password_job: None,
+        page: None,
+    }));
+
+    let router = make_static!(Router::<AppState>::new());
+    unwrap!(routes::register(router));
+    let router = &*router;
 
     info!("joining network...");
     loop {
@@ -181,279 +256,356 @@ async fn main(spawner: Spawner) {
     }
     info!("DHCP is now up!");
 
+    control.gpio_set(0, false).await;
+    let control = &*make_static!(Mutex::<CriticalSectionRawMutex, Control<'static>>::new(control));
+
+    for id in 0..SERVER_POOL_SIZE {
+        unwrap!(spawner.spawn(server_task(id, stack, router, app_state, control)));
+    }
+
+    info!("Listening on TCP:8000...");
+    control.lock().await.gpio_set(0, true).await;
+}
+
+// Each server task owns its socket and buffers, so up to SERVER_POOL_SIZE clients are served
+// at once. Application state and the cyw43 control are shared through mutexes.
+#[embassy_executor::task(pool_size = SERVER_POOL_SIZE)]
+async fn server_task(
+    id: usize,
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    router: &'static Router<AppState>,
+    app_state: &'static Mutex<CriticalSectionRawMutex, AppState>,
+    control: &'static Mutex<CriticalSectionRawMutex, Control<'static>>,
+) -> ! {
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
-    let mut buf = [0; 4096];
+    let mut buf = [0; 1024];
+    let mut parser = RequestParser::new();
+    parser.stream_multipart(true);
 
-    control.gpio_set(0, false).await;
     let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
-    socket.set_timeout(Some(Duration::from_secs(10)));
-
-    info!("Listening on TCP:8000...");
-    control.gpio_set(0, true).await;
 
     loop {
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
         if let Err(e) = socket.accept(8000).await {
-            warn!("accept error: {:?}", e);
+            warn!("server {}: accept error: {:?}", id, e);
             continue;
         }
 
-        info!("Received connection from {:?}", socket.remote_endpoint());
+        info!("server {}: received connection from {:?}", id, socket.remote_endpoint());
+        parser.reset();
 
-        loop {
-            let n = match socket.read(&mut buf).await {
-                Ok(0) => {
-                    warn!("read EOF");
-                    break;
-                }
-                Ok(n) => n,
-                Err(e) => {
-                    warn!("read error: {:?}", e);
-                    break;
-                }
-            };
-
-            info!("rxd {}", from_utf8(&buf[..n]).unwrap());
+        let mut status = ParseStatus::Incomplete;
+        let mut requests_served = 0;
 
-            let mut req = Request::new();
-            let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
-
-            req.parse(&buf[..n], n);
-
-            let mut control_action = None;
-
-            let (http_response, response_length) = match req.path.as_bytes() {
-                b"/" => {
-                    resp.status = 200;
-
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
-
-                    resp.write(b"<html><head><link href=\"/style.css\" rel=\"stylesheet\" /></head><body><h1>Hello /</h1><p>");
-
-                    // Add the Accept-Encoding value if it exists
-                    for (_, header_option) in req.headers.data.iter().enumerate() {
-                        if let (Some(key), value_count, Some(value)) = header_option {
-                            // Append the header key for debugging
-                            resp.write(key.as_bytes());
-                            resp.write(b": ");
-
-                            // for i in 0..*value_count {
-                            //     if let Some(value) = values[i] {
-                            //         // Append the header value for debugging
-                            resp.write(value.as_bytes());
-                            resp.write(b", ");
-                            //     }
-                            // }
-                            resp.write(b"<br>");
-                        }
+        loop {
+            if status == ParseStatus::Incomplete {
+                // Between requests the connection may sit idle, but only for so long
+                let read = if parser.is_empty() {
+                    with_timeout(KEEP_ALIVE_TIMEOUT, socket.read(&mut buf)).await
+                } else {
+                    Ok(socket.read(&mut buf).await)
+                };
+
+                let n = match read {
+                    Ok(Ok(0)) => {
+                        warn!("server {}: read EOF", id);
+                        break;
                     }
+                    Ok(Ok(n)) => n,
+                    Ok(Err(e)) => {
+                        warn!("server {}: read error: {:?}", id, e);
+                        break;
+                    }
+                    Err(_) => {
+                        info!("server {}: idle timeout", id);
+                        break;
+                    }
+                };
 
-                    // Complete the HTML response
-                    resp.write(b"</p></body></html>");
-
-                    resp.generate()
-                }
-                b"/jwt/generate" => {
-                    resp.status = 200;
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
-
-                    let (private_key, public_key) = generate_keys();
-                    let mut public_key_encoded = [0u8; 256];
-                    let mut private_key_encoded = [0u8; 128];
-
-                    let public_key_encoded_length = base64_url_encode(&public_key, &mut public_key_encoded);
-                    let private_key_encoded_length = base64_url_encode(&private_key, &mut private_key_encoded);
+                info!("server {}: rxd {} bytes", id, n);
 
-                    resp.write(b"Public Key: ");
-                    resp.write(&public_key_encoded[..public_key_encoded_length]);
-                    resp.write(b"<br>");
-                    resp.write(b"Private Key: ");
-                    resp.write(&private_key_encoded[..private_key_encoded_length]);
+                // Keep reading until the whole request, including its body, has arrived
+                status = parser.feed(&buf[..n]);
+            }
 
-                    resp.generate()
+            let mut req = Request::new();
+            if let Some(endpoint) = socket.remote_endpoint() {
+                match endpoint.addr {
+                    IpAddress::Ipv4(address) => req.remote_address = address.0,
                 }
-                b"/sd-card/list" => {
-                    resp.status = 200;
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
-
-                    match list_directory(&mut volume_mgr, "/") {
-                        Ok((files, total_files)) => {
-                            for (_, file) in files.iter().enumerate() {
-                                let filename = &file.name[..file.name_len];
-                                resp.write(b"<a target=\"_new\" href=\"/sd-card/edit?filename=");
-                                resp.write(filename);
-                                resp.write(b"\">");
-                                resp.write(filename);
-                                resp.write(b"</a><br>")
+            }
+            let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+            let mut streamed = false;
+            // Set when the guard or the part handler has already answered, the handler isn't run
+            let mut answered = false;
+
+            let parsed = match status {
+                ParseStatus::Incomplete => continue,
+                ParseStatus::Complete => parser.parse(&mut req),
+                ParseStatus::HeadComplete => {
+                    let mut params = RouteParams::new();
+
+                    match parser.parse_head(&mut req) {
+                        Ok(()) => match router.part_handler(&req, &mut params) {
+                            Some(part_handler) => {
+                                streamed = true;
+                                // Nothing of the body reaches the card unless the route's guard lets it.
+                                // A refused body is left unread, streamed closes the connection after answering.
+                                answered = !router.authorize(&req, &mut resp, &mut *app_state.lock().await);
+                                if answered {
+                                    Ok(())
+                                } else {
+                                    match receive_multipart(&mut socket, &parser, &mut buf, &req, &mut resp, &params, part_handler, app_state).await {
+                                        Ok(()) => Ok(()),
+                                        Err(UploadError::Parse(e)) => Err(e),
+                                        // The rest of the body is left unread, only the failure is answered
+                                        Err(UploadError::Part(message)) => {
+                                            resp.status = 500;
+                                            resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+                                            resp.write(message.as_bytes());
+                                            answered = true;
+                                            Ok(())
+                                        }
+                                    }
+                                }
+                            }
+                            None => {
+                                // Not an upload route, the body has to fit in the parser
+                                status = parser.buffer_body();
+                                continue;
                             }
-                        }
-                        Err(e) => {
-                            resp.write(b"Cannot read directory");
-                        }
+                        },
+                        Err(e) => Err(e),
                     }
-
-                    resp.generate()
                 }
-                b"/sd-card/edit" => {
-                    resp.status = 200;
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
-
-                    let mut path_buffer = [0u8; 128];  // Adjust the size as needed
-                    let query_filename = get_query_param_value(
-                        req.query_param_count,
-                        &req.query_param_keys,
-                        &req.query_param_values,
-                        b"filename",
-                    );
+                ParseStatus::Error(e) => Err(e),
+            };
 
-                    if let Some(filename) = query_filename {
+            if let Err(e) = parsed {
+                warn!("server {}: bad request, answering {}", id, e.status());
+                let _ = send_error(&mut socket, e).await;
+                break;
+            }
+            requests_served += 1;
+
+            let (control_action, page) = if answered {
+                (None, None)
+            } else {
+                let mut state = app_state.lock().await;
+                router.dispatch(&req, &mut resp, &mut state);
+
+                // Hashed with the state unlocked, finishing may leave another job, e.g. a rehash
+                while let Some(mut job) = state.password_job.take() {
+                    drop(state);
+                    let matched = job.run().await;
+                    state = app_state.lock().await;
+                    (job.finish)(&req, &mut resp, &mut state, &job.user, matched);
+                }
+                (state.control_action.take(), state.page.take())
+            };
 
-                        if filename.len() <= path_buffer.len() {
-                            path_buffer[..filename.len()].copy_from_slice(filename);
+            if let Some((pin, gpio_state)) = control_action {
+                control.lock().await.gpio_set(pin, gpio_state).await;
+            }
 
-                            if let Ok(file_path_str) = core::str::from_utf8(&path_buffer[..filename.len()]) {
-                                let mut file_data = ByteString::<{ 1024 * 16 }>::new(b"");
-                                let _ = read_file(&mut volume_mgr, file_path_str, &mut file_data);
+            // The parser never saw a streamed body, so it can't tell where a following request starts
+            let keep_alive = !streamed && req.keep_alive() && requests_served < MAX_KEEP_ALIVE_REQUESTS;
+            if keep_alive {
+                resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"keep-alive")));
+                resp.headers.append(ByteString::new(b"Keep-Alive"), Some(ByteString::new(KEEP_ALIVE_HEADER)));
+            } else {
+                resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+            }
 
-                                // ... rest of your code ...
-                                resp.write(&file_path_str.as_bytes());
+            let sent = match page {
+                Some(page) => send_page(&mut socket, &resp, &req, &page).await,
+                None => send_response(&mut socket, &mut resp, app_state).await,
+            };
+            if let Err(e) = sent {
+                warn!("server {}: send error: {:?}", id, e);
+                break;
+            }
 
-                                let mut file_data = ByteString::<{ 1024 * 16 }>::new(b"");
-                                let file_path = core::str::from_utf8(&file_path_str.as_bytes()).unwrap_or("");
+            if !keep_alive {
+                break;
+            }
 
-                                let _ = read_file(&mut volume_mgr, file_path, &mut file_data);
+            // A pipelined request may already be waiting in the buffer
+            status = parser.next_request();
+        }
 
-                                let tpl = r#"
-                                    <form action="/sd-card/save" method="POST">
-                                        <input type="hidden" name="filename" value="{{filename}}" />
-                                        <textarea name="data">{{data}}</textarea>
-                                        <br>
-                                        <input type="submit" value="Save File">
-                                    </form>
-                                    "#;
+        // Send our FIN and give the peer a moment to acknowledge it before the socket is reused
+        socket.close();
+        let _ = with_timeout(Duration::from_secs(1), socket.flush()).await;
+        socket.abort();
+        let _ = socket.flush().await;
+    }
+}
 
-                                let mut tpl_bytes: [u8; 1024] = [0u8; 1024];
-                                tpl_bytes.copy_from_slice(tpl.as_bytes());
+enum UploadError {
+    Parse(ParseError),
+    // What the part handler gave up with
+    Part(&'static str),
+}
 
-                                replace(&mut tpl_bytes, "{{filename}}", file_path);
-                                replace(&mut tpl_bytes, "{{data}}", core::str::from_utf8(&file_data.as_bytes()).unwrap_or(""));
+impl From<ParseError> for UploadError {
+    fn from(error: ParseError) -> Self {
+        UploadError::Parse(error)
+    }
+}
 
-                                resp.write(&tpl_bytes);
-                            }
-                        } else {}
-                    }
-                    resp.generate()
-                }
-                b"/sd-card" => {
-                    resp.status = 200;
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+// Hands a multipart body to the route's part handler as it comes off the socket instead of
+// buffering it. The state is only locked while a piece is handled, never while waiting on the network.
+// Reading stops at the first piece the part handler fails.
+async fn receive_multipart(
+    socket: &mut TcpSocket<'_>,
+    parser: &RequestParser,
+    buf: &mut [u8],
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    params: &RouteParams,
+    part_handler: PartHandler<AppState>,
+    app_state: &Mutex<CriticalSectionRawMutex, AppState>,
+) -> Result<(), UploadError> {
+    let boundary = req.multipart_boundary().ok_or(ParseError::MalformedMultipart)?;
+    let mut reader = MultipartReader::new(boundary)?;
 
-                    let _ = read_file(&mut volume_mgr, "my_file.txt", &mut resp.body);
+    let mut remaining = parser.content_length();
+    let mut received = parser.body();
 
-                    resp.generate()
+    loop {
+        let mut failed = None;
+        {
+            let mut state = app_state.lock().await;
+            reader.feed(received, &mut |event| {
+                if failed.is_none() {
+                    failed = part_handler(req, resp, params, &event, &mut state).err();
                 }
-                b"/query" => {
-                    resp.status = 200;
-                    let mut response_data = ByteString::<BUFFER_SIZE>::new(&[]);
-
-                    response_data.append(b"<html><head><link href=\"/style.css\" rel=\"stylesheet\" /></head><body><h1>Hello /</h1><p>Hello");
-
-                    // Add name of the person
-                    let name = get_query_param_value(req.query_param_count, &req.query_param_keys, &req.query_param_values, b"name");
-                    if let Some(name_value) = name {
-                        resp.write(name_value);
-                    }
-
-                    // Complete the HTML response
-                    resp.write(b"!</p></body></html>");
-
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+            })?;
+        }
+        if let Some(message) = failed {
+            return Err(UploadError::Part(message));
+        }
 
-                    resp.generate()
-                }
-                b"/off" => {
-                    resp.status = 200;
-                    control_action = Some((0, false));
+        remaining -= received.len();
+        if remaining == 0 {
+            break;
+        }
 
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+        let read_length = core::cmp::min(remaining, buf.len());
+        received = match socket.read(&mut buf[..read_length]).await {
+            Ok(0) | Err(_) => return Err(UploadError::Parse(ParseError::MalformedBody)),
+            Ok(n) => &buf[..n],
+        };
+    }
 
+    Ok(reader.finish()?)
+}
 
-                    resp.generate()
-                }
-                b"/on" => {
-                    resp.status = 200;
-                    control_action = Some((0, true));
+// The connection is closed after a parse error, there is no telling where the next request starts
+async fn send_error(socket: &mut TcpSocket<'_>, error: ParseError) -> Result<(), embassy_net::tcp::Error> {
+    let mut resp = Response::<MAX_HEADER_KEY, MAX_HEADER_VALUE>::new();
+    resp.status = error.status();
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+    resp.send(socket).await
+}
 
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+#[derive(Format)]
+enum SendError {
+    Write(embassy_net::tcp::Error),
+    // The card failed after the headers went out, so the body is short and the connection has to close
+    FileRead,
+}
 
+impl From<embassy_net::tcp::Error> for SendError {
+    fn from(error: embassy_net::tcp::Error) -> Self {
+        SendError::Write(error)
+    }
+}
 
-                    resp.generate()
-                }
-                b"/style.css" => {
-                    resp.status = 200;
+// The page is rendered straight into the response, so its length isn't known until the end
+async fn send_page(
+    socket: &mut TcpSocket<'_>,
+    resp: &Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    req: &Request,
+    page: &Page,
+) -> Result<(), SendError> {
+    let mut writer = ResponseWriter::start(socket, resp.status, &resp.headers, &resp.cookies, BodyLength::Chunked).await?;
+    page.render(req, &mut writer).await?;
+    Ok(writer.finish().await?)
+}
 
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/css")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+async fn send_response(
+    socket: &mut TcpSocket<'_>,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    app_state: &Mutex<CriticalSectionRawMutex, AppState>,
+) -> Result<(), SendError> {
+    let file = match resp.file {
+        Some(file) => file,
+        None => return Ok(resp.send(socket).await?),
+    };
+    let file_path = from_utf8(file.path.as_bytes()).unwrap_or("");
+
+    let opened = {
+        let mut state = app_state.lock().await;
+        open_file(&mut state.volume_mgr, file_path).map(|handle| (handle, file_length(&mut state.volume_mgr, handle)))
+    };
+    let (handle, size) = match opened {
+        Ok(opened) => opened,
+        Err(_) => {
+            resp.status = 404;
+            resp.body = ByteString::new(b"");
+            return Ok(resp.send(socket).await?);
+        }
+    };
 
-                    resp.write(b"body{color: #333; font-family: sans-serif;'}h1{}p{}");
+    // The file stays open while it is sent, so every chunk carries on from the last one
+    let result = stream_file(socket, resp, &file, handle, size, app_state).await;
+    close_file(&mut app_state.lock().await.volume_mgr, handle);
+    result
+}
 
+async fn stream_file(
+    socket: &mut TcpSocket<'_>,
+    resp: &Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    file: &FileBody,
+    handle: File,
+    size: Option<u32>,
+    app_state: &Mutex<CriticalSectionRawMutex, AppState>,
+) -> Result<(), SendError> {
+    // Escaping changes the length, so only a raw file of known size gets a Content-Length
+    let length = match (size, file.escape) {
+        (Some(size), Escape::Raw) => BodyLength::Fixed(resp.body.len() + size as usize),
+        _ => BodyLength::Chunked,
+    };
 
-                    resp.generate()
-                }
-                b"/sign-up" => {
-                    if req.method.as_bytes() == b"POST" {
-                        route_sign_up_post(&req, &mut resp, &mut id_store, &mut user_store)
-                    } else {
-                        handle_get_sign_up_route(&req, &mut resp)
-                    }
-                }
-                _ => {
-                    resp.status = 400;
+    let (before, after) = resp.body.as_bytes().split_at(core::cmp::min(file.at, resp.body.len()));
 
-                    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-                    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
+    // Stream the file a block at a time so its size is not limited by the response buffer
+    let mut writer = ResponseWriter::start(socket, resp.status, &resp.headers, &resp.cookies, length).await?;
+    writer.write_body(before).await?;
 
-                    resp.generate()
-                }
-            };
+    let mut chunk = [0u8; 512];
 
-            if let Some((pin, state)) = control_action {
-                control.gpio_set(pin, state).await;
+    loop {
+        // The card is only locked while reading, never while waiting on the network
+        let read = read_chunk(&mut app_state.lock().await.volume_mgr, handle, &mut chunk);
+
+        match read {
+            Ok(0) => break,
+            Ok(n) => {
+                // Escaping one block at a time keeps to one chunk per block
+                let mut escaped = ByteString::<{ 512 * 6 }>::new(b"");
+                let _ = write_escaped(&mut escaped, &chunk[..n], file.escape);
+                writer.write_body(escaped.as_bytes()).await?;
             }
-
-            match socket.write_all(&http_response[..response_length]).await {
-                Ok(()) => {
-                    socket.close();
-                }
-                Err(e) => {
-                    warn!("write error: {:?}", e);
-                    break;
-                }
-            };
+            Err(_) => return Err(SendError::FileRead),
         }
     }
-}
 
-fn handle_get_sign_up_route(
-    req: &Request,
-    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
-)
-    -> ([u8; BUFFER_SIZE], usize) {
-    resp.status = 200;
-
-    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
-    resp.headers.append(ByteString::new(b"Connection"), Some(ByteString::new(b"close")));
-    let tpl = include_str!("templates/sign-up.html");
-    resp.write(tpl.as_bytes());
-
-    resp.generate()
+    writer.write_body(after).await?;
+    Ok(writer.finish().await?)
 }
-
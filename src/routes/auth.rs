@@ -0,0 +1,115 @@
+use crate::claims::{ClaimValue, Claims};
+use crate::entropy::Csprng;
+use crate::http::{ByteString, Request, Response, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::jwt::{Algorithm, Validation};
+use crate::routes::{now, AppState};
+use crate::user::Role;
+
+// Who a request's session or token says is signed in
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AuthUser {
+    pub user_id: u16,
+    pub role: Role,
+}
+
+// The token an API client sends in an Authorization: Bearer header
+pub fn request_token(req: &Request) -> Option<&[u8]> {
+    let authorization = req.authorization()?;
+    if authorization.len() > 7 && authorization[..7].eq_ignore_ascii_case(b"Bearer ") {
+        return Some(&authorization[7..]);
+    }
+    None
+}
+
+// The claim holding AppState::user_epoch
+pub const EPOCH_CLAIM: &[u8] = b"epoch";
+
+// The signed in user. Browsers are known by their session, API clients by a genuine token
+// that hasn't expired and was issued since the last boot. A request with a token is judged by
+// the token alone.
+pub fn authenticate(req: &Request, state: &mut AppState) -> Option<AuthUser> {
+    let token = match request_token(req) {
+        Some(token) => token,
+        None => {
+            let (_, session) = state.sessions.current(req, now())?;
+            return Some(AuthUser { user_id: session.user_id, role: Role::from_u8(session.role) });
+        }
+    };
+
+    // An HMAC client's shared secret can't mint a login
+    let claims = match state.jwt_keys.verify_any(token, &Validation::new(), now()) {
+        Ok((Algorithm::Es256, claims)) => claims,
+        _ => return None,
+    };
+    if claims.custom(EPOCH_CLAIM) != Some(&ClaimValue::Number(state.user_epoch as i64)) {
+        return None;
+    }
+    let user_id = core::str::from_utf8(claims.sub?.as_bytes()).ok()?.parse().ok()?;
+    Some(AuthUser { user_id, role: Role::from_u8(claims.role?) })
+}
+
+// The claims of an HMAC client's bearer token, the client being one of main's HS256_CLIENTS.
+// These tokens never stand for a user, authenticate turns them away.
+pub fn authenticate_client(req: &Request, state: &AppState) -> Option<Claims> {
+    match state.jwt_keys.verify_any(request_token(req)?, &Validation::new(), now()) {
+        Ok((Algorithm::Hs256, claims)) => Some(claims),
+        _ => None,
+    }
+}
+
+// For protected routes, answers 401 when nobody is signed in so the handler can just return
+pub fn require_user(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    state: &mut AppState,
+) -> Option<AuthUser> {
+    let user = authenticate(req, state);
+
+    if user.is_none() {
+        resp.status = 401;
+        resp.headers.append(ByteString::new(b"WWW-Authenticate"), Some(ByteString::new(b"Bearer")));
+        resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+        resp.write(b"Please <a href=\"/login\">log in</a> first");
+    }
+
+    user
+}
+
+// Like require_user, but a signed in user without the role gets a 403
+pub fn require_role(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    state: &mut AppState,
+    role: Role,
+) -> Option<AuthUser> {
+    let user = require_user(req, resp, state)?;
+
+    if !user.role.allows(role) {
+        resp.status = 403;
+        resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+        resp.write(b"You don't have permission to do that");
+        return None;
+    }
+
+    Some(user)
+}
+
+// Route guard for Router::guard
+pub fn require_admin(req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, state: &mut AppState) -> bool {
+    require_role(req, resp, state, Role::Admin).is_some()
+}
+
+// Signs the browser in with a new session, in place of any it had. Fails when every session
+// slot is taken by one that hasn't expired yet.
+pub fn sign_in(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    state: &mut AppState,
+    user_id: u16,
+    role: Role,
+) -> Result<(), &'static str> {
+    if let Some((id, _)) = state.sessions.current(req, now()) {
+        state.sessions.destroy(&id);
+    }
+    state.sessions.start(&mut Csprng, resp, user_id, role as u8, now()).map(|_| ())
+}
@@ -0,0 +1,68 @@
+use embedded_io_async::Write as AsyncWrite;
+
+use crate::csrf::{self, CSRF_TOKEN_LENGTH};
+use crate::entropy::Csprng;
+use crate::http::{usize_to_bytes, ByteString, Request, Response, RouteParams, MAX_HEADERS, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::auth::authenticate;
+use crate::routes::{AppState, Page};
+use crate::template::compiled::home;
+use crate::user::Role;
+
+const HIDDEN_HEADERS: [&[u8]; 2] = [b"Cookie", b"Authorization"];
+
+pub fn route_home_get(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.status = 200;
+
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+    let user = authenticate(req, state);
+    let user_id = user.map(|user| user.user_id);
+    let admin = user.is_some_and(|user| user.role.allows(Role::Admin));
+
+    // For the log out and LED forms
+    let mut csrf_token = [0u8; CSRF_TOKEN_LENGTH];
+    if user_id.is_some() {
+        csrf_token = csrf::issue(&mut Csprng, resp);
+    }
+
+    state.page = Some(Page::Home { user_id, admin, csrf_token });
+}
+
+pub async fn render_home<W: AsyncWrite>(
+    req: &Request,
+    user_id: Option<u16>,
+    admin: bool,
+    csrf_token: &[u8],
+    out: &mut W,
+) -> Result<(), W::Error> {
+    let mut user_id_bytes = [0u8; 10];
+    let user_id_length = user_id.map_or(0, |user_id| usize_to_bytes(user_id as usize, &mut user_id_bytes));
+
+    // List the request headers for debugging. Credentials are left out, a script on the page
+    // could read them back and HttpOnly would be for nothing.
+    let mut headers: [home::HeadersItem; MAX_HEADERS] = core::array::from_fn(|_| home::HeadersItem { name: b"", value: b"" });
+    let mut header_count = 0;
+    for (key, _, value) in req.headers.data.iter() {
+        if let (Some(key), Some(value)) = (key, value) {
+            if HIDDEN_HEADERS.iter().any(|hidden| key.as_bytes().eq_ignore_ascii_case(hidden)) {
+                continue;
+            }
+            headers[header_count] = home::HeadersItem { name: key.as_bytes(), value: value.as_bytes() };
+            header_count += 1;
+        }
+    }
+
+    let context = home::Context {
+        signed_in: user_id.is_some(),
+        admin,
+        user_id: &user_id_bytes[..user_id_length],
+        csrf_token,
+        headers: &headers[..header_count],
+    };
+    home::render_async(&context, out).await
+}
@@ -0,0 +1,60 @@
+use crate::base64::base64_url_encode;
+use crate::csrf;
+use crate::entropy::Csprng;
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::AppState;
+
+// Asks before rotating. The key is only rotated by a POST carrying this form's CSRF token, so
+// a link on another site can't do it.
+pub fn route_jwt_generate_get(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    _state: &mut AppState,
+) {
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+    let csrf_token = csrf::issue(&mut Csprng, resp);
+    resp.write(br#"<form action="/jwt/generate" method="POST"><input type="hidden" name="csrf_token" value=""#);
+    resp.write(&csrf_token);
+    resp.write(br#"" /><input type="submit" value="Generate a new signing key"></form>"#);
+}
+
+// Rotates the token signing key. Tokens signed by the key before keep working, older ones stop.
+// Admins only, see routes::register.
+pub fn route_jwt_generate(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+    if !csrf::verify(req) {
+        resp.status = 403;
+        resp.write(b"The form has expired, please <a href=\"/jwt/generate\">try again</a>");
+        return;
+    }
+
+    if state.jwt_keys.rotate().is_err() {
+        resp.status = 500;
+        resp.write(b"Unable to generate a new key, the current one is still in use");
+        return;
+    }
+
+    resp.status = 200;
+    if state.jwt_keys.save(&mut state.volume_mgr).is_err() {
+        resp.write(b"Unable to save the keys, the new key will be lost on reboot<br>");
+    }
+
+    let key = state.jwt_keys.current();
+    let mut public_key_encoded = [0u8; 128];
+    let public_key_encoded_length = base64_url_encode(&key.public_key, &mut public_key_encoded);
+
+    resp.write(b"Key ID: ");
+    resp.write(&key.kid);
+    resp.write(b"<br>");
+    resp.write(b"Public Key: ");
+    resp.write(&public_key_encoded[..public_key_encoded_length]);
+}
@@ -0,0 +1,2 @@
+pub mod off;
+pub mod on;
\ No newline at end of file
@@ -0,0 +1,22 @@
+use crate::csrf;
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::AppState;
+
+// Admins only, see routes::register. Only the form on the home page may switch the LED
+pub fn route_led_off(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    if !csrf::verify(req) {
+        resp.status = 403;
+        resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+        resp.write(b"The form has expired, please <a href=\"/\">try again</a>");
+        return;
+    }
+
+    resp.status = 303;
+    state.control_action = Some((0, false));
+    resp.headers.append(ByteString::new(b"Location"), Some(ByteString::new(b"/")));
+}
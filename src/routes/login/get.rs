@@ -0,0 +1,43 @@
+use embedded_io_async::Write as AsyncWrite;
+
+use crate::csrf;
+use crate::entropy::Csprng;
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::{AppState, Page};
+use crate::template::compiled::login;
+
+pub fn route_login_get(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.status = 200;
+    render_login_form(resp, state, false, false);
+}
+
+// Shared with the POST handler, which shows the form again when the credentials are wrong.
+// Every rendering comes with a fresh CSRF token.
+pub fn render_login_form(resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, state: &mut AppState, login_error: bool, csrf_error: bool) {
+    let csrf_token = csrf::issue(&mut Csprng, resp);
+
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+    state.page = Some(Page::Login { csrf_token, login_error, csrf_error });
+}
+
+// The username entered is kept in the form when it's shown again
+pub async fn render_login<W: AsyncWrite>(
+    req: &Request,
+    csrf_token: &[u8],
+    login_error: bool,
+    csrf_error: bool,
+    out: &mut W,
+) -> Result<(), W::Error> {
+    let context = login::Context {
+        csrf_token,
+        csrf_error,
+        username: req.post(b"username").unwrap_or(b""),
+        login_error,
+    };
+    login::render_async(&context, out).await
+}
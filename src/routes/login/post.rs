@@ -0,0 +1,134 @@
+use crate::csrf;
+use crate::claims::Claims;
+use crate::http::{usize_to_bytes, ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::jwt::{MAX_TOKEN_LENGTH, TOKEN_LIFETIME};
+use crate::routes::auth::{sign_in, EPOCH_CLAIM};
+use crate::routes::login::get::render_login_form;
+use crate::routes::{now, too_many_password_checks, AppState, PasswordJob, PasswordWork};
+use crate::user::User;
+
+fn wants_json(req: &Request) -> bool {
+    req.headers.get(b"Accept")
+        .map_or(false, |accept| accept.windows(16).any(|window| window.eq_ignore_ascii_case(b"application/json")))
+}
+
+// Browsers get a session and are sent home, clients asking for JSON get a token in the body to
+// use as a Bearer token. The password is checked by finish_login.
+pub fn route_login_post(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    let username = req.post(b"username").unwrap_or(b"");
+    let password = req.post(b"password").unwrap_or(b"");
+
+    // Only the form path starts a session, so only it can be abused to log a victim into an
+    // attacker's account. JSON clients get the token back in a body another site can't read.
+    if !wants_json(req) && !csrf::verify(req) {
+        resp.status = 403;
+        render_login_form(resp, state, false, true);
+        return;
+    }
+
+    if !state.password_throttle.allow(now(), req.remote_address, username) {
+        too_many_password_checks(resp);
+        return;
+    }
+
+    // An unknown username is checked against the dummy, so it takes as long as a wrong password
+    // and the timing doesn't give away which accounts exist
+    let user = state.user_store.find(|_, user| user.has_username(username)).cloned().unwrap_or_else(User::dummy);
+    state.password_job = Some(PasswordJob::new(user, password, PasswordWork::Check, finish_login));
+}
+
+fn finish_login(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    state: &mut AppState,
+    user: &User,
+    matched: bool,
+) {
+    let wants_json = wants_json(req);
+
+    // The record may have changed while the password was being checked, the check only counts
+    // for the hash it was made against. The dummy's id 0 is never in the store.
+    let current = state.user_store.get(&user.id).filter(|current| matched && current.password == user.password);
+
+    let (user_id, role) = match current {
+        Some(current) => (current.id, current.role),
+        None => {
+            resp.status = 401;
+            if wants_json {
+                resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"application/json")));
+                resp.write(b"{\"error\":\"invalid_credentials\"}");
+            } else {
+                render_login_form(resp, state, true, false);
+            }
+            return;
+        }
+    };
+
+    // The plaintext is only at hand here, so this is where stale hashes get upgraded
+    if user.needs_rehash() {
+        let password = req.post(b"password").unwrap_or(b"");
+        state.password_job = Some(PasswordJob::new(user.clone(), password, PasswordWork::Set, store_rehash));
+    }
+
+    if !wants_json {
+        if sign_in(req, resp, state, user_id, role).is_err() {
+            resp.status = 503;
+            resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/plain")));
+            resp.write(b"Too many people are signed in, please try again later");
+            return;
+        }
+        resp.status = 303;
+        resp.headers.append(ByteString::new(b"Location"), Some(ByteString::new(b"/")));
+        return;
+    }
+
+    let mut subject = [0u8; 5];
+    let subject_length = usize_to_bytes(user_id as usize, &mut subject);
+
+    let issued_at = now();
+    let mut claims = Claims::new()
+        .subject(&subject[..subject_length])
+        .role(role as u8)
+        .issued_at(issued_at)
+        .expires_at(issued_at + TOKEN_LIFETIME);
+    let epoch = claims.set_number(EPOCH_CLAIM, state.user_epoch as i64);
+
+    let mut token = [0u8; MAX_TOKEN_LENGTH];
+    let token_length = match epoch {
+        Ok(()) => state.jwt_keys.sign(&claims, &mut token),
+        Err(_) => 0,
+    };
+    if token_length == 0 {
+        resp.status = 500;
+        resp.write(b"Unable to sign the login token");
+        return;
+    }
+
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"application/json")));
+    resp.write(b"{\"token\":\"");
+    resp.write(&token[..token_length]);
+    resp.write(b"\",\"token_type\":\"Bearer\"}");
+}
+
+// Swaps the upgraded hash in, unless another login got there first
+fn store_rehash(
+    _req: &Request,
+    _resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    state: &mut AppState,
+    user: &User,
+    _matched: bool,
+) {
+    if let Some(stored) = state.user_store.get_mut(&user.id) {
+        if stored.needs_rehash() {
+            stored.salt = user.salt;
+            stored.iterations = user.iterations;
+            stored.password = user.password;
+        }
+    }
+}
@@ -0,0 +1 @@
+pub mod post;
\ No newline at end of file
@@ -0,0 +1,23 @@
+use crate::csrf;
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::{now, AppState};
+
+// Ends the browser's session. Bearer tokens can't be revoked, API clients just drop theirs.
+pub fn route_logout(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    // Only the form on the home page logs out, not another site posting here
+    if !csrf::verify(req) {
+        resp.status = 403;
+        resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+        resp.write(b"The form has expired, please <a href=\"/\">try again</a>");
+        return;
+    }
+
+    resp.status = 303;
+    state.sessions.end(req, resp, now());
+    resp.headers.append(ByteString::new(b"Location"), Some(ByteString::new(b"/login")));
+}
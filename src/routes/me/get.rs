@@ -0,0 +1,42 @@
+use crate::http::{usize_to_bytes, ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::jwt::MAX_PAYLOAD_LENGTH;
+use crate::routes::auth::{authenticate_client, require_user};
+use crate::routes::AppState;
+
+pub fn route_me_get(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    // An HMAC client gets back the claims its token was accepted with
+    if let Some(claims) = authenticate_client(req, state) {
+        let mut json = [0u8; MAX_PAYLOAD_LENGTH];
+        match claims.write_json(&mut json) {
+            Ok(length) => {
+                resp.status = 200;
+                resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"application/json")));
+                resp.write(&json[..length]);
+            }
+            Err(_) => resp.status = 500,
+        }
+        return;
+    }
+
+    let user = match require_user(req, resp, state) {
+        Some(user) => user,
+        None => return,
+    };
+
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"application/json")));
+
+    let mut number = [0u8; 10];
+    resp.write(b"{\"user_id\":");
+    let length = usize_to_bytes(user.user_id as usize, &mut number);
+    resp.write(&number[..length]);
+    resp.write(b",\"role\":");
+    let length = usize_to_bytes(user.role as usize, &mut number);
+    resp.write(&number[..length]);
+    resp.write(b"}");
+}
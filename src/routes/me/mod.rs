@@ -0,0 +1 @@
+pub mod get;
\ No newline at end of file
@@ -1 +1,165 @@
-pub mod sign_up;
\ No newline at end of file
+use embassy_time::Instant;
+use embedded_io_async::Write as AsyncWrite;
+
+use crate::csrf::CSRF_TOKEN_LENGTH;
+use crate::entropy::Csprng;
+use crate::http::{usize_to_bytes, ByteString, Request, Response, Router, MAX_HEADER_KEY, MAX_HEADER_VALUE, MAX_POST_PARAM_LENGTH};
+use crate::jwt::KeyManager;
+use crate::kv::KeyValueStore;
+use crate::sdcard::SdVolumeManager;
+use crate::session::SessionStore;
+use crate::user::{PasswordThrottle, User, THROTTLE_WINDOW};
+
+pub mod auth;
+pub mod home;
+pub mod jwt;
+pub mod led;
+pub mod login;
+pub mod logout;
+pub mod me;
+pub mod query;
+pub mod sd_card;
+pub mod sign_up;
+pub mod style;
+pub mod well_known;
+
+// Everything the handlers share between requests
+pub struct AppState {
+    pub id_store: KeyValueStore<u16, u16>,
+    pub user_store: KeyValueStore<u16, User>,
+    // Drawn at boot and put in every login token. Users only live in RAM, so after a reboot
+    // a token's user id may belong to someone else; a token from an earlier boot is turned away.
+    pub user_epoch: u32,
+    pub sessions: SessionStore,
+    // Shared by login and sign-up, which both hash a password
+    pub password_throttle: PasswordThrottle,
+    // Keys the login tokens are signed with
+    pub jwt_keys: KeyManager,
+    pub volume_mgr: SdVolumeManager,
+    // GPIO change requested by a handler, applied by the server once the response is built
+    pub control_action: Option<(u8, bool)>,
+    // Password hashing requested by a handler, see PasswordJob
+    pub password_job: Option<PasswordJob>,
+    // Page a handler answered with, see Page
+    pub page: Option<Page>,
+}
+
+// Called with the state locked again once a PasswordJob is done, with the job's user (hashed
+// anew by a Set) and whether a Check matched
+pub type PasswordHandler = fn(&Request, &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, &mut AppState, &User, bool);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PasswordWork {
+    // Whether the password matches the user's hash
+    Check,
+    // Hashes the password into the user under a fresh salt
+    Set,
+}
+
+// Hashing a password takes long enough that doing it in a handler would hold the state, and
+// with it every other server task. A handler leaves the work here instead, like control_action;
+// the server does it with the state unlocked and yielding as it goes, then calls `finish`.
+pub struct PasswordJob {
+    pub user: User,
+    pub password: ByteString<MAX_POST_PARAM_LENGTH>,
+    pub work: PasswordWork,
+    pub finish: PasswordHandler,
+}
+
+impl PasswordJob {
+    pub fn new(user: User, password: &[u8], work: PasswordWork, finish: PasswordHandler) -> PasswordJob {
+        PasswordJob { user, password: ByteString::new(password), work, finish }
+    }
+
+    // True when a Check matched, a Set always succeeds
+    pub async fn run(&mut self) -> bool {
+        match self.work {
+            PasswordWork::Check => self.user.check_password(self.password.as_bytes()).await,
+            PasswordWork::Set => {
+                self.user.set_password(&mut Csprng, self.password.as_bytes()).await;
+                true
+            }
+        }
+    }
+}
+
+// Pages from src/templates aren't bound by the size of the response body: a handler leaves
+// the page here with what it needs besides the request, and the server renders it straight
+// into the response once the status and headers are out.
+pub enum Page {
+    Home { user_id: Option<u16>, admin: bool, csrf_token: [u8; CSRF_TOKEN_LENGTH] },
+    Login { csrf_token: [u8; CSRF_TOKEN_LENGTH], login_error: bool, csrf_error: bool },
+    Query,
+    SignUp(sign_up::get::SignUpForm),
+    // Only the form, for answering the form's own POST
+    SignUpForm(sign_up::get::SignUpForm),
+}
+
+impl Page {
+    pub async fn render<W: AsyncWrite>(&self, req: &Request, out: &mut W) -> Result<(), W::Error> {
+        match self {
+            Page::Home { user_id, admin, csrf_token } => home::get::render_home(req, *user_id, *admin, csrf_token, out).await,
+            Page::Login { csrf_token, login_error, csrf_error } => {
+                login::get::render_login(req, csrf_token, *login_error, *csrf_error, out).await
+            }
+            Page::Query => query::get::render_query(req, out).await,
+            Page::SignUp(form) => sign_up::get::render_sign_up(req, form, out).await,
+            Page::SignUpForm(form) => sign_up::get::render_sign_up_form(req, form, out).await,
+        }
+    }
+}
+
+// Seconds since boot, the clock sessions are measured against
+pub fn now() -> u64 {
+    Instant::now().as_secs()
+}
+
+// Replaces a page that didn't fit in the response body, instead of sending it cut off
+pub fn page_too_large(resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>) {
+    resp.status = 500;
+    resp.body = ByteString::new(b"The page is too large");
+    resp.file = None;
+}
+
+// Answers 429 when PasswordThrottle has no check left for this request
+pub fn too_many_password_checks(resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>) {
+    let mut retry_after = [0u8; 20];
+    let retry_after_length = usize_to_bytes(THROTTLE_WINDOW as usize, &mut retry_after);
+
+    resp.status = 429;
+    resp.headers.append(ByteString::new(b"Retry-After"), Some(ByteString::new(&retry_after[..retry_after_length])));
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/plain")));
+    resp.write(b"Too many attempts, please try again in a few seconds");
+}
+
+pub fn register(router: &mut Router<AppState>) -> Result<(), &'static str> {
+    router.get("/", home::get::route_home_get)?;
+    router.get("/query", query::get::route_query_get)?;
+    router.get("/style.css", style::get::route_style_get)?;
+    router.get("/jwt/generate", jwt::generate::route_jwt_generate_get)?;
+    router.post("/jwt/generate", jwt::generate::route_jwt_generate)?;
+    router.get("/.well-known/jwks.json", well_known::jwks::route_well_known_jwks)?;
+    router.get("/sd-card", sd_card::get::route_sd_card_get)?;
+    router.get("/sd-card/list", sd_card::list::route_sd_card_list)?;
+    router.get("/sd-card/edit", sd_card::edit::route_sd_card_edit)?;
+    router.get("/sd-card/upload", sd_card::upload::route_sd_card_upload_get)?;
+    router.upload("/sd-card/upload", sd_card::upload::route_sd_card_upload_part, sd_card::upload::route_sd_card_upload_post)?;
+    router.post("/on", led::on::route_led_on)?;
+    router.post("/off", led::off::route_led_off)?;
+    router.get("/sign-up", sign_up::get::route_sign_up_get)?;
+    router.post("/sign-up", sign_up::post::route_sign_up_post)?;
+    router.get("/login", login::get::route_login_get)?;
+    router.post("/login", login::post::route_login_post)?;
+    router.post("/logout", logout::post::route_logout)?;
+    router.get("/me", me::get::route_me_get)?;
+
+    // Guards cover every path under their prefix, routes added later included. The longest
+    // prefix wins, so /sd-card/upload answers to its own guard rather than the /sd-card one.
+    router.guard("/sd-card", auth::require_admin)?;
+    router.guard("/sd-card/upload", sd_card::upload::guard_upload)?;
+    router.guard("/on", auth::require_admin)?;
+    router.guard("/off", auth::require_admin)?;
+    router.guard("/jwt/generate", auth::require_admin)?;
+
+    Ok(())
+}
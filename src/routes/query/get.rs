@@ -0,0 +1,24 @@
+use embedded_io_async::Write as AsyncWrite;
+
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::{AppState, Page};
+use crate::template::compiled::query;
+
+pub fn route_query_get(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.status = 200;
+    state.page = Some(Page::Query);
+
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+}
+
+pub async fn render_query<W: AsyncWrite>(req: &Request, out: &mut W) -> Result<(), W::Error> {
+    let context = query::Context {
+        name: req.get(b"name").unwrap_or(b""),
+    };
+    query::render_async(&context, out).await
+}
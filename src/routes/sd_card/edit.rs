@@ -0,0 +1,48 @@
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::{page_too_large, AppState};
+use crate::sdcard::file_size;
+use crate::template::{escape, render, Context, Escape, Value};
+
+pub fn route_sd_card_edit(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+    let filename = match req.get(b"filename") {
+        Some(filename) => filename,
+        None => return,
+    };
+
+    if let Ok(file_path) = core::str::from_utf8(filename) {
+        escape(file_path.as_bytes(), Escape::Html, |piece| resp.write(piece));
+
+        // The contents are streamed from the card into the textarea when the response is sent
+        const FORM_START: &str = r#"
+            <form action="/sd-card/save" method="POST">
+                <input type="hidden" name="filename" value="{{filename}}" />
+                <textarea name="data">"#;
+        const FORM_END: &str = r#"</textarea>
+                <br>
+                <input type="submit" value="Save File">
+            </form>
+            "#;
+
+        let values = [("filename", Value::Str(filename))];
+        let context = Context::new(&values);
+
+        let rendered = render(FORM_START, &context, resp).and_then(|_| {
+            // A file that isn't there yet is edited from empty
+            if file_size(&mut state.volume_mgr, file_path).is_ok() {
+                resp.embed_file(filename, Escape::Html);
+            }
+            render(FORM_END, &context, resp)
+        });
+        if rendered.is_err() {
+            page_too_large(resp);
+        }
+    }
+}
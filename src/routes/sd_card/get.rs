@@ -0,0 +1,14 @@
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::AppState;
+
+pub fn route_sd_card_get(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    _state: &mut AppState,
+) {
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+    resp.send_file(b"my_file.txt");
+}
@@ -0,0 +1,34 @@
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::AppState;
+use crate::sdcard::list_directory;
+use crate::template::{escape, Escape};
+use crate::url::url_encode;
+
+pub fn route_sd_card_list(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+    match list_directory(&mut state.volume_mgr, "/") {
+        Ok((files, total_files)) => {
+            for file in files[..total_files].iter() {
+                let filename = &file.name[..file.name_len];
+                let mut encoded_filename = [0u8; 64 * 3];
+                let encoded_length = url_encode(filename, &mut encoded_filename);
+
+                resp.write(b"<a target=\"_new\" href=\"/sd-card/edit?filename=");
+                resp.write(&encoded_filename[..encoded_length]);
+                resp.write(b"\">");
+                escape(filename, Escape::Html, |piece| resp.write(piece));
+                resp.write(b"</a><br>")
+            }
+        }
+        Err(_) => {
+            resp.write(b"Cannot read directory");
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod edit;
+pub mod get;
+pub mod list;
+pub mod upload;
\ No newline at end of file
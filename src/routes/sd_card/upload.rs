@@ -0,0 +1,103 @@
+use crate::http::{ByteString, MultipartEvent, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::csrf;
+use crate::entropy::Csprng;
+use crate::routes::{auth, AppState};
+use crate::sdcard::{append_to_file, close_appending, write_file};
+use crate::template::{escape, Escape};
+
+pub fn route_sd_card_upload_get(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    _state: &mut AppState,
+) {
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+
+    // The CSRF token rides in the URL, see guard_upload
+    let csrf_token = csrf::issue(&mut Csprng, resp);
+    resp.write(br#"
+        <form action="/sd-card/upload?csrf_token="#);
+    resp.write(&csrf_token);
+    resp.write(br#"" method="POST" enctype="multipart/form-data">
+            <input type="file" name="file" multiple />
+            <br>
+            <input type="submit" value="Upload">
+        </form>
+        "#);
+}
+
+// Called for every piece of the body as it comes off the socket, file parts are written to the
+// card chunk by chunk so their size isn't limited by memory. The file stays open from the first
+// chunk to the end of its part.
+pub fn route_sd_card_upload_part(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    event: &MultipartEvent,
+    state: &mut AppState,
+) -> Result<(), &'static str> {
+    match event {
+        MultipartEvent::Start(part) => {
+            if let Some(file_path) = part.filename.as_ref().and_then(|filename| upload_file_path(filename.as_bytes())) {
+                // Creates the file, or empties it when it already exists
+                write_file(&mut state.volume_mgr, file_path, b"").map_err(|_| "Unable to create the file on the SD card")?;
+            }
+        }
+        MultipartEvent::Data(part, data) => {
+            if let Some(file_path) = part.filename.as_ref().and_then(|filename| upload_file_path(filename.as_bytes())) {
+                append_to_file(&mut state.volume_mgr, file_path, data).map_err(|_| "Unable to write file to the SD card")?;
+            }
+        }
+        MultipartEvent::End(part) => {
+            if let Some(file_path) = part.filename.as_ref().and_then(|filename| upload_file_path(filename.as_bytes())) {
+                close_appending(&mut state.volume_mgr, file_path);
+                resp.write(b"Uploaded ");
+                escape(file_path.as_bytes(), Escape::Html, |piece| resp.write(piece));
+                resp.write(b"<br>");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Only runs once every part has been written
+pub fn route_sd_card_upload_post(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    _state: &mut AppState,
+) {
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+    resp.write(b"<a href=\"/sd-card/list\">Back to files</a>");
+}
+
+// Route guard for Router::guard. Admins only, and an upload has to come from the form, which is
+// checked before any of the body reaches the card.
+pub fn guard_upload(req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, state: &mut AppState) -> bool {
+    if !auth::require_admin(req, resp, state) {
+        return false;
+    }
+
+    if req.method.as_bytes() == b"POST" && !csrf::verify_query(req) {
+        resp.status = 403;
+        resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+        resp.write(b"The form has expired, please <a href=\"/sd-card/upload\">try again</a>");
+        return false;
+    }
+
+    true
+}
+
+// Browsers may send a full client side path, only the last component is kept.
+// Files always land in the root directory of the card.
+fn upload_file_path(filename: &[u8]) -> Option<&str> {
+    let name = filename.rsplit(|&b| b == b'/' || b == b'\\').next()?;
+
+    match core::str::from_utf8(name) {
+        Ok("") | Ok(".") | Ok("..") | Err(_) => None,
+        Ok(name) => Some(name),
+    }
+}
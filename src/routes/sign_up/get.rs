@@ -0,0 +1,67 @@
+use embedded_io_async::Write as AsyncWrite;
+
+use crate::csrf::{self, CSRF_TOKEN_LENGTH};
+use crate::entropy::Csprng;
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::{AppState, Page};
+use crate::template::compiled::{partials, sign_up};
+
+// What the sign-up form shows besides the username entered, which comes from the request
+#[derive(Copy, Clone, Default)]
+pub struct SignUpForm {
+    pub csrf_token: [u8; CSRF_TOKEN_LENGTH],
+    pub csrf_error: bool,
+    pub signup_success: bool,
+    pub user_error: bool,
+    pub username_taken_error: bool,
+    pub store_full_error: bool,
+    pub password_error: bool,
+    pub password_confirm_error: bool,
+    pub password_match_error: bool,
+}
+
+pub fn route_sign_up_get(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.status = 200;
+
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+    let csrf_token = csrf::issue(&mut Csprng, resp);
+    // The form fields come from the same partial the POST handler answers with
+    state.page = Some(Page::SignUp(SignUpForm { csrf_token, ..SignUpForm::default() }));
+}
+
+pub async fn render_sign_up<W: AsyncWrite>(req: &Request, form: &SignUpForm, out: &mut W) -> Result<(), W::Error> {
+    let context = sign_up::Context {
+        csrf_token: &form.csrf_token,
+        csrf_error: form.csrf_error,
+        signup_success: form.signup_success,
+        username: req.post(b"username").unwrap_or(b""),
+        user_error: form.user_error,
+        username_taken_error: form.username_taken_error,
+        store_full_error: form.store_full_error,
+        password_error: form.password_error,
+        password_confirm_error: form.password_confirm_error,
+        password_match_error: form.password_match_error,
+    };
+    sign_up::render_async(&context, out).await
+}
+
+pub async fn render_sign_up_form<W: AsyncWrite>(req: &Request, form: &SignUpForm, out: &mut W) -> Result<(), W::Error> {
+    let context = partials::sign_up::Context {
+        csrf_token: &form.csrf_token,
+        csrf_error: form.csrf_error,
+        signup_success: form.signup_success,
+        username: req.post(b"username").unwrap_or(b""),
+        user_error: form.user_error,
+        username_taken_error: form.username_taken_error,
+        store_full_error: form.store_full_error,
+        password_error: form.password_error,
+        password_confirm_error: form.password_confirm_error,
+        password_match_error: form.password_match_error,
+    };
+    partials::sign_up::render_async(&context, out).await
+}
@@ -1,124 +1,103 @@
-use crate::http::{BUFFER_SIZE, Request, Response, MAX_HEADER_KEY, MAX_HEADER_VALUE, ByteString};
-use crate::kv::KeyValueStore;
-use crate::template::{process_if, replace};
+use crate::csrf;
+use crate::http::{Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE, ByteString};
+use crate::routes::auth::sign_in;
+use crate::routes::sign_up::get::SignUpForm;
+use crate::routes::{now, too_many_password_checks, AppState, Page, PasswordJob, PasswordWork};
 use crate::user::User;
-use crate::include_str_checked;
-
-struct FormErrors {
-    user_error: bool,
-    password_error: bool,
-    password_confirm_error: bool,
-    password_match_error: bool,
-}
+use crate::entropy::Csprng;
 
 pub fn route_sign_up_post(
     req: &Request,
     resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
-    mut id_store: &mut KeyValueStore::<u16, u16>,
-    mut user_store: &mut KeyValueStore::<u16, User>,
-) -> ([u8; BUFFER_SIZE], usize) {
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
     resp.status = 200;
 
     if req.method.as_bytes() == b"POST" {
         let entered_username = req.post(b"username");
         let entered_password = req.post(b"password");
         let password_confirmation = req.post(b"password2");
-        let mut signup_success = false;
 
-        const TPL_LENGTH: usize = 4096;
-        const TPL: &str = include_str_checked!("../../templates/partials/sign-up.html", TPL_LENGTH);
-        let mut tpl_bytes: [u8; TPL_LENGTH] = [0; TPL_LENGTH];
-        let tpl_slice = TPL.as_bytes();
-        if tpl_slice.len() <= tpl_bytes.len() {
-            tpl_bytes[..tpl_slice.len()].copy_from_slice(tpl_slice);
-        } else {
-            resp.write(b"Overflow!");
-            return resp.generate();
+        // The partial is the form again when something is wrong, so it needs a token of its own
+        let csrf_token = csrf::issue(&mut Csprng, resp);
+        let mut form = SignUpForm { csrf_token, ..SignUpForm::default() };
+
+        // Another site could otherwise sign the browser up and in to an account of its choosing
+        let csrf_ok = csrf::verify(req);
+        if !csrf_ok {
+            resp.status = 403;
+            form.csrf_error = true;
         }
 
         match (entered_username, entered_password, password_confirmation) {
+            _ if !csrf_ok => {}
+            (Some(usr), _, _) if state.user_store.find(|_, user| user.has_username(usr)).is_some() => {
+                form.username_taken_error = true;
+            }
             (Some(usr), Some(passwd), Some(passwd2)) if passwd == passwd2 => {
+                if !state.password_throttle.allow(now(), req.remote_address, usr) {
+                    too_many_password_checks(resp);
+                    return;
+                }
+
+                // The account is made by finish_sign_up once the password is hashed
                 let mut user = User::new();
-                user.id = id_store.get(&0).cloned().unwrap_or(1);
                 user.username[..usr.len().min(32)].copy_from_slice(&usr[..usr.len().min(32)]);
-                user.password[..passwd.len().min(32)].copy_from_slice(&passwd[..passwd.len().min(32)]);
-                user_store.add(user.id, user.clone()).unwrap();
-
-                //-- Increment the next User ID
-                id_store.set(0, user.id + 1).unwrap();
-
-                signup_success = true;
+                state.password_job = Some(PasswordJob::new(user, passwd, PasswordWork::Set, finish_sign_up));
+                return;
             }
-            _ => {
-                // Initialize error flags
-                let mut errors = FormErrors {
-                    user_error: false,
-                    password_error: false,
-                    password_confirm_error: false,
-                    password_match_error: false,
-                };
+            (None, _, _) => form.user_error = true,
+            (_, None, _) => form.password_error = true,
+            (_, _, None) => form.password_confirm_error = true,
+            _ => form.password_match_error = true,
+        }
 
-                // Determine which errors are present
-                match (entered_username, entered_password, password_confirmation) {
-                    (None, _, _) => errors.user_error = true,
-                    (_, None, _) => errors.password_error = true,
-                    (_, Some(password), Some(password2)) if password != password2 => errors.password_match_error = true,
-                    (_, _, None) => errors.password_confirm_error = true,
-                    _ => (),
-                };
+        render_sign_up(resp, state, form);
+    } else {
+        resp.status = 404;
+        resp.headers.append(ByteString::new(b"Content-Type"),  Some(ByteString::new(b"text/html")));
+        return;
+    }
+}
 
-                // Handle error scenarios
-                let mut user_error = false;
-                let mut password_error = false;
-                let mut password_match_error = false;
-                let mut password_confirm_error = false;
-                match (entered_username, entered_password, password_confirmation) {
-                    (None, _, _) => user_error = true,
-                    (_, None, _) => password_error = true,
-                    (_, _, None) => password_confirm_error = true,
-                    _ => password_match_error = true,
-                };
+fn finish_sign_up(
+    req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    state: &mut AppState,
+    hashed: &User,
+    _matched: bool,
+) {
+    let csrf_token = csrf::issue(&mut Csprng, resp);
+    let mut form = SignUpForm { csrf_token, ..SignUpForm::default() };
 
-                if signup_success {
-                    process_if(&mut tpl_bytes, signup_success, "{{#if signup_success}}", "{{/if signup_success}}", None, None);
-                } else {
-                    let error_conditions = [
-                        (errors.user_error, "{{#if user_error}}", "{{/if user_error}}"),
-                        (errors.password_error, "{{#if password_error}}", "{{/if password_error}}"),
-                        (errors.password_confirm_error, "{{#if password_confirm_error}}", "{{/if password_confirm_error}}"),
-                        (errors.password_match_error, "{{#if password_match_error}}", "{{/if password_match_error}}"),
-                    ];
+    // Someone else may have taken the name while the password was being hashed
+    if state.user_store.find(|_, user| user.username == hashed.username).is_some() {
+        form.username_taken_error = true;
+        render_sign_up(resp, state, form);
+        return;
+    }
 
-                    for (condition, if_placeholder, if_end_placeholder) in &error_conditions {
-                        process_if(&mut tpl_bytes, *condition, if_placeholder, if_end_placeholder, None, None);
-                    }
-                }
-            }
-        }
+    let mut user = hashed.clone();
+    user.id = state.id_store.get(&0).cloned().unwrap_or(1);
+    if state.user_store.add(user.id, user.clone()).is_err() {
+        resp.status = 503;
+        form.store_full_error = true;
+        render_sign_up(resp, state, form);
+        return;
+    }
 
-        //-- Username
-        let usr = match entered_username {
-            None => {b""}
-            Some(usr) => {usr}
-        };
-        replace(&mut tpl_bytes, "{{username}}", core::str::from_utf8(&usr[..usr.len()]).unwrap_or(""));
+    //-- Increment the next User ID
+    state.id_store.set(0, user.id + 1).unwrap();
 
-        process_if(&mut tpl_bytes,
-                   signup_success,
-                   "{{#if signup_success}}",
-                   "{{/if signup_success}}",
-                   Some("{{#else signup_success}}"),
-                   Some("{{/else signup_success}}"));
+    //-- Log the new user in straight away. Without a free session they can still log in later.
+    let _ = sign_in(req, resp, state, user.id, user.role);
 
-        resp.write(&tpl_bytes[..]);
-    } else {
-        resp.status = 404;
-        resp.headers.append(ByteString::new(b"Content-Type"),  Some(ByteString::new(b"text/html")));
-        resp.headers.append(ByteString::new(b"Connection"),  Some(ByteString::new(b"close")));
-        return resp.generate();
-    }
+    form.signup_success = true;
+    render_sign_up(resp, state, form);
+}
 
-    //-- Request close connection, do not support keep-alive
-    resp.headers.append(ByteString::new(b"Connection"),  Some(ByteString::new(b"close")));
-    resp.generate()
-}
\ No newline at end of file
+fn render_sign_up(resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, state: &mut AppState, form: SignUpForm) {
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/html")));
+    state.page = Some(Page::SignUpForm(form));
+}
@@ -0,0 +1,15 @@
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::AppState;
+
+pub fn route_style_get(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    _state: &mut AppState,
+) {
+    resp.status = 200;
+
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"text/css")));
+
+    resp.write(b"body{color: #333; font-family: sans-serif;'}h1{}p{}");
+}
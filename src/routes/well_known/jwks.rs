@@ -0,0 +1,19 @@
+use crate::http::{ByteString, Request, Response, RouteParams, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::routes::AppState;
+
+// Public keys for services that verify the device's tokens, both keys are listed during a rotation
+pub fn route_well_known_jwks(
+    _req: &Request,
+    resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+    _params: &RouteParams,
+    state: &mut AppState,
+) {
+    resp.status = 200;
+    resp.headers.append(ByteString::new(b"Content-Type"), Some(ByteString::new(b"application/json")));
+    // Short enough that a rotation is picked up before the previous key goes away
+    resp.headers.append(ByteString::new(b"Cache-Control"), Some(ByteString::new(b"public, max-age=300")));
+
+    let mut jwks = ByteString::<512>::new(b"");
+    state.jwt_keys.write_jwks(&mut jwks);
+    resp.write(jwks.as_bytes());
+}
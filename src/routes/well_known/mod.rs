@@ -0,0 +1 @@
+pub mod jwks;
\ No newline at end of file
@@ -7,7 +7,7 @@ use embedded_hal::blocking::delay::DelayUs;
 use embassy_sync::blocking_mutex::CriticalSectionMutex;
 use critical_section::with;
 use embassy_usb::UsbDeviceState::Default;
-use crate::http::ByteString;
+use crate::http::{ByteString, MAX_FILE_PATH_LENGTH};
 use crate::sdcard::SdCardError::{FileOpenError, VolumeCloseError, VolumeError};
 use lorawan::parser::AsPhyPayloadBytes;
 
@@ -50,6 +50,7 @@ pub enum SdCardError {
     DirectoryOpenError,
     DirectoryCloseError,
     FileOpenError,
+    FileNotFound,
     FileCloseError,
     FileReadError,
     FileWriteError,
@@ -209,115 +210,131 @@ pub async fn read_file_async(file_path: &'static str) {
     });
 }
 
-pub fn read_file<'a, const N: usize>(
-    mut volume_mgr: &mut VolumeManager<SdCard<Spi<SPI1, Async>, Output<PIN_9>, Delayer>, MyTimeSource>,
-    file_path: &'static str,
+// Keeps the volume and root directory open between calls, so a file can stay open while its
+// reader or writer waits on the network. The card only lets a volume be opened once.
+pub struct SdVolumeManager {
+    volume_mgr: VolumeManager<SdCard<Spi<'static, SPI1, Async>, Output<'static, PIN_9>, Delayer>, MyTimeSource>,
+    root: Option<(Volume, Directory)>,
+    // Files being appended to, left open from one append_to_file to the next until close_appending
+    appending: [Option<(ByteString<MAX_FILE_PATH_LENGTH>, File)>; MAX_APPENDING],
+}
+
+// The volume manager has room for four open files, this leaves the rest for readers
+const MAX_APPENDING: usize = 2;
+
+impl SdVolumeManager {
+    pub fn new(volume_mgr: VolumeManager<SdCard<Spi<'static, SPI1, Async>, Output<'static, PIN_9>, Delayer>, MyTimeSource>) -> Self {
+        SdVolumeManager { volume_mgr, root: None, appending: [None; MAX_APPENDING] }
+    }
+
+    fn root_dir(&mut self) -> Result<Directory, SdCardError> {
+        if let Some((_, root_dir)) = self.root {
+            return Ok(root_dir);
+        }
+
+        let volume = self.volume_mgr.open_volume(VolumeIdx(0)).map_err(|_| SdCardError::VolumeError)?;
+        let root_dir = match self.volume_mgr.open_root_dir(volume) {
+            Ok(dir) => dir,
+            Err(_) => {
+                let _ = self.volume_mgr.close_volume(volume);
+                return Err(SdCardError::DirectoryOpenError);
+            }
+        };
+
+        self.root = Some((volume, root_dir));
+        Ok(root_dir)
+    }
+
+    // A file still open for appending can't be opened again, so anything else done to it
+    // closes it first
+    fn release(&mut self, file_path: &str) {
+        for slot in self.appending.iter_mut() {
+            if let Some((path, file)) = *slot {
+                if path.as_bytes() == file_path.as_bytes() {
+                    let _ = self.volume_mgr.close_file(file);
+                    *slot = None;
+                }
+            }
+        }
+    }
+}
+
+pub fn read_file<const N: usize>(
+    volume_mgr: &mut SdVolumeManager,
+    file_path: &str,
     out: &mut ByteString<N>
 ) -> Result<usize, SdCardError> {
-    let volume = volume_mgr.open_volume(embedded_sdmmc::VolumeIdx(0)).map_err(|_| SdCardError::VolumeError)?;
-    let root_dir = volume_mgr.open_root_dir(volume).map_err(|_| SdCardError::DirectoryOpenError)?;
-    let my_file = volume_mgr.open_file_in_dir(root_dir, file_path, embedded_sdmmc::Mode::ReadOnly).map_err(|_| SdCardError::FileOpenError)?;
+    let my_file = open_file(volume_mgr, file_path)?;
 
     let mut bytes_read = 0;
+    let mut result = Ok(());
 
     loop {
-        let eof = volume_mgr.file_eof(my_file).map_err(|_| SdCardError::FileReadError)?;
-        if eof {
-            break;
-        }
-
         let mut buffer = [0u8; 32];
-        let read = volume_mgr.read(my_file, &mut buffer).map_err(|_| SdCardError::FileReadError)?;
-        out.append(&buffer[..read]);
-        bytes_read += read;
+        match read_chunk(volume_mgr, my_file, &mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                out.append(&buffer[..read]);
+                bytes_read += read;
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
     }
 
-    // Close the file and handle potential errors
-    volume_mgr.close_file(my_file).map_err(|_| SdCardError::FileCloseError)?;
-    volume_mgr.close_dir(root_dir).map_err(|_| SdCardError::DirectoryCloseError)?;
-    volume_mgr.close_volume(volume).map_err(|_| SdCardError::VolumeCloseError)?;
-
-    Ok(bytes_read)
+    close_file(volume_mgr, my_file);
+    result.map(|_| bytes_read)
 }
 
-pub fn write_file<'a, const N: usize>(
-    mut volume_mgr: &mut VolumeManager<SdCard<Spi<SPI1, Async>, Output<PIN_9>, Delayer>, MyTimeSource>,
-    file_path: &'static str,
+pub fn write_file(
+    volume_mgr: &mut SdVolumeManager,
+    file_path: &str,
     data: &[u8]
 ) -> Result<(), SdCardError> {
-    let volume = volume_mgr.open_volume(embedded_sdmmc::VolumeIdx(0)).map_err(|_| SdCardError::VolumeError)?;
-    let root_dir = volume_mgr.open_root_dir(volume).map_err(|_| SdCardError::DirectoryOpenError)?;
+    volume_mgr.release(file_path);
+    let root_dir = volume_mgr.root_dir()?;
 
     // Open the file in write mode, create it if it doesn't exist
-    let my_file = volume_mgr.open_file_in_dir(root_dir, file_path, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate).map_err(|_| SdCardError::FileOpenError)?;
-
-    // Write data to the file
-    volume_mgr.write(my_file, data).map_err(|_| SdCardError::FileWriteError)?;
+    let my_file = volume_mgr.volume_mgr.open_file_in_dir(root_dir, file_path, Mode::ReadWriteCreateOrTruncate)
+        .map_err(|_| SdCardError::FileOpenError)?;
 
-    // Close the file and handle potential errors
-    volume_mgr.close_file(my_file).map_err(|_| SdCardError::FileCloseError)?;
-    volume_mgr.close_dir(root_dir).map_err(|_| SdCardError::DirectoryCloseError)?;
-    volume_mgr.close_volume(volume).map_err(|_| SdCardError::VolumeCloseError)?;
+    // Write data to the file, closing it either way
+    let result = volume_mgr.volume_mgr.write(my_file, data).map_err(|_| SdCardError::FileWriteError);
+    volume_mgr.volume_mgr.close_file(my_file).map_err(|_| SdCardError::FileCloseError)?;
 
-    Ok(())
+    result
 }
 
 pub fn check_file_exists(
-    mut volume_mgr: &mut VolumeManager<SdCard<Spi<SPI1, Async>, Output<PIN_9>, Delayer>, MyTimeSource>,
-    file_path: &'static str
+    volume_mgr: &mut SdVolumeManager,
+    file_path: &str
 ) -> bool {
-    let volume = match volume_mgr.open_volume(embedded_sdmmc::VolumeIdx(0)) {
-        Ok(vol) => vol,
-        Err(_) => return false,
-    };
-
-    let root_dir = match volume_mgr.open_root_dir(volume) {
-        Ok(dir) => dir,
-        Err(_) => return false,
-    };
-
-    let file_open_result = volume_mgr.open_file_in_dir(root_dir, file_path, embedded_sdmmc::Mode::ReadOnly);
-
-    // Close the directory and volume after the check
-    let _ = volume_mgr.close_dir(root_dir);
-    let _ = volume_mgr.close_volume(volume);
-
-    // Check if file open was successful
-    file_open_result.is_ok()
+    file_size(volume_mgr, file_path).is_ok()
 }
 
 pub fn delete_file(
-    mut volume_mgr: &mut VolumeManager<SdCard<Spi<SPI1, Async>, Output<PIN_9>, Delayer>, MyTimeSource>,
+    volume_mgr: &mut SdVolumeManager,
     file_path: &str,
 ) -> Result<(), SdCardError> {
-    // Open the volume
-    let volume = volume_mgr.open_volume(VolumeIdx(0)).map_err(|_| SdCardError::VolumeError)?;
-
-    // Open the root directory
-    let root_dir = volume_mgr.open_root_dir(volume).map_err(|_| SdCardError::DirectoryOpenError)?;
+    volume_mgr.release(file_path);
+    let root_dir = volume_mgr.root_dir()?;
 
     // Delete the file from the directory
-    volume_mgr.delete_file_in_dir(root_dir, file_path).map_err(|_| SdCardError::FileDeleteError)?;
-
-    // Close the directory
-    volume_mgr.close_dir(root_dir).map_err(|_| SdCardError::DirectoryCloseError)?;
-
-    Ok(())
+    volume_mgr.volume_mgr.delete_file_in_dir(root_dir, file_path).map_err(|_| SdCardError::FileDeleteError)
 }
 
 pub fn list_directory(
-    volume_mgr: &mut VolumeManager<SdCard<Spi<SPI1, Async>, Output<PIN_9>, Delayer>, MyTimeSource>,
+    volume_mgr: &mut SdVolumeManager,
     dir_path: &str,
 ) -> Result<([FileInfo; 64], usize), SdCardError> {
-    // Open the volume
-    let volume = volume_mgr.open_volume(VolumeIdx(0)).map_err(|_| SdCardError::VolumeError)?;
-
     // Open the specified directory
-    let root_dir = volume_mgr.open_root_dir(volume).map_err(|_| SdCardError::DirectoryOpenError)?;
+    let root_dir = volume_mgr.root_dir()?;
     let directory = if dir_path == "/" {
         root_dir
     } else {
-        volume_mgr.open_dir(root_dir, ShortFileName::create_from_str(dir_path).map_err(|_| SdCardError::DirectoryOpenError)?)
+        volume_mgr.volume_mgr.open_dir(root_dir, ShortFileName::create_from_str(dir_path).map_err(|_| SdCardError::DirectoryOpenError)?)
             .map_err(|_| SdCardError::DirectoryOpenError)?
     };
 
@@ -330,7 +347,7 @@ pub fn list_directory(
     }; 64];
     let mut file_count = 0;
 
-    volume_mgr.iterate_dir(directory, |entry| {
+    let result = volume_mgr.volume_mgr.iterate_dir(directory, |entry| {
         if file_count < 64 {
             let mut name = [0u8; 64];
             let basename = entry.name.base_name();
@@ -360,38 +377,117 @@ pub fn list_directory(
             files[file_count].mtime = entry.mtime;
             file_count += 1;
         }
-    }).map_err(|_| SdCardError::DirectoryReadError)?;
+    }).map_err(|_| SdCardError::DirectoryReadError);
 
-    // Close the directory and volume
-    volume_mgr.close_dir(directory).map_err(|_| SdCardError::DirectoryCloseError)?;
-    volume_mgr.close_volume(volume).map_err(|_| SdCardError::VolumeCloseError)?;
+    // The root directory stays open for the next call
+    if dir_path != "/" {
+        volume_mgr.volume_mgr.close_dir(directory).map_err(|_| SdCardError::DirectoryCloseError)?;
+    }
 
-    Ok((files, file_count))
+    result.map(|_| (files, file_count))
 }
 
+// Appends to a file, which is left open so the next append to it doesn't have to reopen it and
+// seek to the end. close_appending closes it once the last piece is written.
 pub fn append_to_file(
-    mut volume_mgr: &mut VolumeManager<SdCard<Spi<SPI1, Async>, Output<PIN_9>, Delayer>, MyTimeSource>,
+    volume_mgr: &mut SdVolumeManager,
     file_path: &str,
     data: &[u8],
 ) -> Result<(), SdCardError> {
-    // Open the volume
-    let volume = volume_mgr.open_volume(VolumeIdx(0)).map_err(|_| SdCardError::VolumeError)?;
+    let open = volume_mgr.appending.iter().flatten()
+        .find(|(path, _)| path.as_bytes() == file_path.as_bytes())
+        .map(|(_, file)| *file);
+
+    let file = match open {
+        Some(file) => file,
+        None => {
+            // An upload that broke off never closes its file, so with no slot free the first one
+            // is closed. Should that upload still be going, its next append just reopens the file.
+            let slot = match volume_mgr.appending.iter().position(|slot| slot.is_none()) {
+                Some(slot) => slot,
+                None => {
+                    if let Some((_, file)) = volume_mgr.appending[0].take() {
+                        let _ = volume_mgr.volume_mgr.close_file(file);
+                    }
+                    0
+                }
+            };
+            let root_dir = volume_mgr.root_dir()?;
+            let file = volume_mgr.volume_mgr.open_file_in_dir(root_dir, file_path, Mode::ReadWriteAppend)
+                .map_err(|_| SdCardError::FileOpenError)?;
+            volume_mgr.appending[slot] = Some((ByteString::new(file_path.as_bytes()), file));
+            file
+        }
+    };
 
-    // Open the root directory
-    let root_dir = volume_mgr.open_root_dir(volume).map_err(|_| SdCardError::DirectoryOpenError)?;
+    // Write data to the file, a failed write closes it
+    let result = volume_mgr.volume_mgr.write(file, data).map_err(|_| SdCardError::FileWriteError);
+    if result.is_err() {
+        volume_mgr.release(file_path);
+    }
 
-    // Open the file in ReadWriteAppend mode
-    let file = volume_mgr.open_file_in_dir(root_dir, file_path, Mode::ReadWriteAppend)
-        .map_err(|_| SdCardError::FileOpenError)?;
+    result
+}
+
+// Closes a file append_to_file left open, which writes out its size
+pub fn close_appending(volume_mgr: &mut SdVolumeManager, file_path: &str) {
+    volume_mgr.release(file_path);
+}
+
+// Looks up a file's size without keeping it open, None when the size cannot be determined
+pub fn file_size(
+    volume_mgr: &mut SdVolumeManager,
+    file_path: &str,
+) -> Result<Option<u32>, SdCardError> {
+    let file = open_file(volume_mgr, file_path)?;
+    let size = file_length(volume_mgr, file);
+    close_file(volume_mgr, file);
 
-    // Write data to the file
-    volume_mgr.write(file, data).map_err(|_| SdCardError::FileWriteError)?;
+    Ok(size)
+}
 
-    // Close the file
-    volume_mgr.close_file(file).map_err(|_| SdCardError::FileCloseError)?;
+pub fn file_length(volume_mgr: &mut SdVolumeManager, file: File) -> Option<u32> {
+    volume_mgr.volume_mgr.file_length(file).ok()
+}
+
+// Opens a file for reading with read_chunk. Only the caller holds it, so the card can be used
+// by others between chunks; it has to be given back with close_file.
+pub fn open_file(
+    volume_mgr: &mut SdVolumeManager,
+    file_path: &str,
+) -> Result<File, SdCardError> {
+    volume_mgr.release(file_path);
+    let root_dir = volume_mgr.root_dir()?;
+    volume_mgr.volume_mgr.open_file_in_dir(root_dir, file_path, Mode::ReadOnly).map_err(|err| match err {
+        embedded_sdmmc::Error::NotFound => SdCardError::FileNotFound,
+        _ => SdCardError::FileOpenError,
+    })
+}
+
+// Reads on from where the last read of the file stopped, filling as much of the buffer as
+// possible. Zero means the end of the file.
+pub fn read_chunk(
+    volume_mgr: &mut SdVolumeManager,
+    file: File,
+    buffer: &mut [u8],
+) -> Result<usize, SdCardError> {
+    let mut bytes_read = 0;
 
-    // Close the directory
-    volume_mgr.close_dir(root_dir).map_err(|_| SdCardError::DirectoryCloseError)?;
+    while bytes_read < buffer.len() {
+        if volume_mgr.volume_mgr.file_eof(file).map_err(|_| SdCardError::FileReadError)? {
+            break;
+        }
+
+        match volume_mgr.volume_mgr.read(file, &mut buffer[bytes_read..]) {
+            Ok(0) => break,
+            Ok(read) => bytes_read += read,
+            Err(_) => return Err(SdCardError::FileReadError),
+        }
+    }
+
+    Ok(bytes_read)
+}
 
-    Ok(())
+pub fn close_file(volume_mgr: &mut SdVolumeManager, file: File) {
+    let _ = volume_mgr.volume_mgr.close_file(file);
 }
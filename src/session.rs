@@ -0,0 +1,257 @@
+use rand::RngCore;
+
+use crate::base64::{base64_url_decode, base64_url_encode};
+use crate::cookie::{Cookie, SameSite};
+use crate::hmac::{constant_time_eq, hmac_sha256};
+use crate::http::{Request, Response, MAX_HEADER_KEY, MAX_HEADER_VALUE};
+use crate::kv::{KeyValueStore, Serializable};
+
+pub const SESSION_COOKIE: &[u8] = b"session";
+pub const SESSION_ID_LENGTH: usize = 16;
+// base64url of the id, a dot, then base64url of its HMAC-SHA256
+pub const SESSION_COOKIE_LENGTH: usize = 22 + 1 + 43;
+// Seconds a session lives at most, and how long it may go unused
+pub const SESSION_LIFETIME: u64 = 60 * 60 * 24;
+pub const SESSION_IDLE_TIMEOUT: u64 = 60 * 30;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SessionId(pub [u8; SESSION_ID_LENGTH]);
+
+impl Serializable for SessionId {
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        if buffer.len() >= SESSION_ID_LENGTH {
+            buffer[..SESSION_ID_LENGTH].copy_from_slice(&self.0);
+            SESSION_ID_LENGTH
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Session {
+    pub user_id: u16,
+    pub role: u8,
+    // Seconds since boot, like everything else time related in here
+    pub expires_at: u64,
+    pub last_seen: u64,
+}
+
+impl Serializable for Session {
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        if buffer.len() < 19 {
+            return 0;
+        }
+
+        buffer[0..2].copy_from_slice(&self.user_id.to_be_bytes());
+        buffer[2] = self.role;
+        buffer[3..11].copy_from_slice(&self.expires_at.to_be_bytes());
+        buffer[11..19].copy_from_slice(&self.last_seen.to_be_bytes());
+        19
+    }
+}
+
+// Sessions live in RAM and the cookie only carries a random id signed with a key that never
+// leaves the device, so a forged or guessed cookie is rejected before the store is consulted
+pub struct SessionStore {
+    sessions: KeyValueStore<SessionId, Session>,
+    key: [u8; 32],
+}
+
+impl SessionStore {
+    pub fn new(key: [u8; 32]) -> SessionStore {
+        SessionStore {
+            sessions: KeyValueStore::new(),
+            key,
+        }
+    }
+
+    pub fn create<R: RngCore>(&mut self, rng: &mut R, user_id: u16, role: u8, now: u64) -> Result<SessionId, &'static str> {
+        if self.sessions.is_full() {
+            self.purge_expired(now);
+        }
+
+        let mut id = SessionId([0; SESSION_ID_LENGTH]);
+        rng.fill_bytes(&mut id.0);
+
+        let session = Session {
+            user_id,
+            role,
+            expires_at: now + SESSION_LIFETIME,
+            last_seen: now,
+        };
+
+        self.sessions.add(id, session)?;
+        Ok(id)
+    }
+
+    // Looks a session up and marks it as used, expired sessions are dropped on the way
+    pub fn get(&mut self, id: &SessionId, now: u64) -> Option<Session> {
+        let session = self.sessions.get_mut(id)?;
+
+        if is_expired(session, now) {
+            self.sessions.remove(id);
+            return None;
+        }
+
+        session.last_seen = now;
+        Some(*session)
+    }
+
+    pub fn destroy(&mut self, id: &SessionId) {
+        self.sessions.remove(id);
+    }
+
+    pub fn purge_expired(&mut self, now: u64) {
+        self.sessions.retain(|_, session| !is_expired(session, now));
+    }
+
+    // The session the request's cookie points at, if the cookie is genuine and the session alive
+    pub fn current(&mut self, req: &Request, now: u64) -> Option<(SessionId, Session)> {
+        let id = self.verify_cookie(req.cookie(SESSION_COOKIE)?)?;
+        let session = self.get(&id, now)?;
+        Some((id, session))
+    }
+
+    // Creates a session and hands its cookie to the browser
+    pub fn start<R: RngCore>(
+        &mut self,
+        rng: &mut R,
+        resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>,
+        user_id: u16,
+        role: u8,
+        now: u64,
+    ) -> Result<SessionId, &'static str> {
+        let id = self.create(rng, user_id, role, now)?;
+        let value = self.cookie_value(&id);
+
+        resp.set_cookie(&Cookie::new(SESSION_COOKIE, &value)
+            .path(b"/")
+            .max_age(SESSION_LIFETIME as u32)
+            .http_only()
+            .same_site(SameSite::Lax));
+
+        Ok(id)
+    }
+
+    // Forgets the request's session, if any, and clears the cookie
+    pub fn end(&mut self, req: &Request, resp: &mut Response<MAX_HEADER_KEY, MAX_HEADER_VALUE>, now: u64) {
+        if let Some((id, _)) = self.current(req, now) {
+            self.destroy(&id);
+        }
+
+        resp.set_cookie(&Cookie::removal(SESSION_COOKIE).path(b"/"));
+    }
+
+    pub fn cookie_value(&self, id: &SessionId) -> [u8; SESSION_COOKIE_LENGTH] {
+        let mut value = [0u8; SESSION_COOKIE_LENGTH];
+
+        let id_length = base64_url_encode(&id.0, &mut value);
+        value[id_length] = b'.';
+        base64_url_encode(&hmac_sha256(&self.key, &id.0), &mut value[id_length + 1..]);
+
+        value
+    }
+
+    pub fn verify_cookie(&self, value: &[u8]) -> Option<SessionId> {
+        if value.len() != SESSION_COOKIE_LENGTH || value[22] != b'.' {
+            return None;
+        }
+
+        let mut id = SessionId([0; SESSION_ID_LENGTH]);
+        if base64_url_decode(&value[..22], &mut id.0) != SESSION_ID_LENGTH {
+            return None;
+        }
+
+        let mut signature = [0u8; 32];
+        if base64_url_decode(&value[23..], &mut signature) != 32 {
+            return None;
+        }
+
+        if constant_time_eq(&signature, &hmac_sha256(&self.key, &id.0)) {
+            Some(id)
+        } else {
+            None
+        }
+    }
+}
+
+fn is_expired(session: &Session, now: u64) -> bool {
+    now >= session.expires_at || now >= session.last_seen + SESSION_IDLE_TIMEOUT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::mock::StepRng;
+
+    #[test]
+    fn test_session_cookie() {
+        let mut store = SessionStore::new([7; 32]);
+        let mut rng = StepRng::new(1, 1);
+
+        let id = store.create(&mut rng, 3, 1, 100).unwrap();
+        let value = store.cookie_value(&id);
+        assert_eq!(store.verify_cookie(&value), Some(id));
+
+        // Any change to the id or the signature is caught
+        let mut tampered = value;
+        tampered[0] = if tampered[0] == b'A' { b'B' } else { b'A' };
+        assert_eq!(store.verify_cookie(&tampered), None);
+
+        let mut tampered = value;
+        tampered[SESSION_COOKIE_LENGTH - 5] = if tampered[SESSION_COOKIE_LENGTH - 5] == b'A' { b'B' } else { b'A' };
+        assert_eq!(store.verify_cookie(&tampered), None);
+
+        // A cookie signed with another key is worthless
+        let other = SessionStore::new([8; 32]);
+        assert_eq!(other.verify_cookie(&value), None);
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let mut store = SessionStore::new([7; 32]);
+        let mut rng = StepRng::new(1, 1);
+
+        let id = store.create(&mut rng, 3, 1, 100).unwrap();
+        assert_eq!(store.get(&id, 200).map(|session| session.user_id), Some(3));
+
+        // Use keeps it alive, until the absolute lifetime runs out
+        let mut now = 200;
+        while now + SESSION_IDLE_TIMEOUT / 2 < 100 + SESSION_LIFETIME {
+            now += SESSION_IDLE_TIMEOUT / 2;
+            assert!(store.get(&id, now).is_some());
+        }
+        assert!(store.get(&id, 100 + SESSION_LIFETIME).is_none());
+
+        // Idle sessions go away
+        let id = store.create(&mut rng, 4, 1, 100).unwrap();
+        assert!(store.get(&id, 100 + SESSION_IDLE_TIMEOUT).is_none());
+
+        let id = store.create(&mut rng, 5, 1, 100).unwrap();
+        store.destroy(&id);
+        assert!(store.get(&id, 101).is_none());
+    }
+
+    #[test]
+    fn test_current_session() {
+        let mut store = SessionStore::new([7; 32]);
+        let mut rng = StepRng::new(1, 1);
+        let id = store.create(&mut rng, 9, 2, 100).unwrap();
+
+        let mut buf = [0u8; 256];
+        let head = b"GET / HTTP/1.1\r\nCookie: theme=dark; session=";
+        buf[..head.len()].copy_from_slice(head);
+        buf[head.len()..head.len() + SESSION_COOKIE_LENGTH].copy_from_slice(&store.cookie_value(&id));
+        let length = head.len() + SESSION_COOKIE_LENGTH + 4;
+        buf[length - 4..length].copy_from_slice(b"\r\n\r\n");
+
+        let mut req = Request::new();
+        assert_eq!(req.parse(&buf[..length], length), Ok(()));
+
+        let (current_id, session) = store.current(&req, 150).unwrap();
+        assert_eq!(current_id, id);
+        assert_eq!(session.user_id, 9);
+        assert_eq!(session.role, 2);
+    }
+}
@@ -1,241 +1,475 @@
-use crate::http::ByteString;
+use core::fmt::Write as _;
+use embedded_io_async::Write as AsyncWrite;
+use crate::http::{ByteString, Response};
+
+// Deepest nesting of `{{#for}}` loops a runtime template may use, anything deeper ends the render
+const MAX_LOOP_DEPTH: usize = 4;
+// Blocks open at once within one runtime template, more make it malformed
+const MAX_BLOCK_DEPTH: usize = 16;
+
+// A value a runtime template can print, branch on or loop over
+#[derive(Copy, Clone, Debug)]
+pub enum Value<'a> {
+    Bool(bool),
+    Int(i64),
+    Str(&'a [u8]),
+    List(&'a [Context<'a>]),
+}
+
+impl<'a> Value<'a> {
+    // What `{{#if}}` goes by: false, zero, empty strings and empty lists are all false
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Bool(value) => *value,
+            Value::Int(value) => *value != 0,
+            Value::Str(value) => !value.is_empty(),
+            Value::List(items) => !items.is_empty(),
+        }
+    }
+}
 
-const TEMPLATE_SIZE: usize = 1024;
+// Named values a template is rendered from, borrowed so it never runs out of room
+#[derive(Copy, Clone, Debug)]
+pub struct Context<'a> {
+    entries: &'a [(&'a str, Value<'a>)],
+}
 
-const MAX_DICT_ENTRIES: usize = 10; // Adjust this based on your needs
+impl<'a> Context<'a> {
+    pub fn new(entries: &'a [(&'a str, Value<'a>)]) -> Self {
+        Context { entries }
+    }
 
-#[derive(Copy, Clone, PartialEq)]
-struct KeyValuePair<'a> {
-    key: &'a str,
-    value: &'a str,
+    pub fn get(&self, name: &str) -> Option<Value<'a>> {
+        self.entries.iter().find(|(key, _)| *key == name).map(|(_, value)| *value)
+    }
 }
 
-#[derive(Copy, Clone)]
-struct StaticDict<'a> {
-    entries: [Option<KeyValuePair<'a>>; MAX_DICT_ENTRIES],
-    count: usize,
+// Why a runtime template didn't render in full
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RenderError<E> {
+    // The template, or a partial or layout it uses, breaks the grammar build.rs enforces
+    Template(&'static str),
+    // The sink gave up
+    Output(E),
 }
 
-impl<'a> StaticDict<'a> {
-    pub fn new() -> Self {
-        StaticDict {
-            entries: [None; MAX_DICT_ENTRIES],
-            count: 0,
-        }
+// How a value is made safe for the spot in the page it lands in
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Escape {
+    // Trusted markup, written as is
+    Raw,
+    // Element content, the five characters HTML gives a meaning are replaced
+    Html,
+    // Inside a tag. Everything but alphanumerics becomes a character reference, which holds up
+    // even when the attribute isn't quoted.
+    Attribute,
+}
+
+// Where rendered output goes. Unlike a scratch buffer a sink says when something didn't fit.
+pub trait Sink {
+    type Error;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+// Keeps what fits
+impl<const N: usize> Sink for ByteString<N> {
+    type Error = &'static str;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let fits = self.len() + bytes.len() <= N;
+        self.append(bytes);
+        if fits { Ok(()) } else { Err("Output too long") }
     }
+}
 
-    pub fn insert(&mut self, key: &'a str, value: &'a str) {
-        if self.count < MAX_DICT_ENTRIES {
-            self.entries[self.count] = Some(KeyValuePair { key, value });
-            self.count += 1;
-        }
-        // Handle overflow case if necessary
+impl<const N: usize, const M: usize> Sink for Response<N, M> {
+    type Error = &'static str;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        self.body.write_bytes(bytes)
     }
+}
 
-    pub fn get(&self, key: &str) -> Option<&'a str> {
-        for entry in self.entries.iter() {
-            if let Some(KeyValuePair { key: k, value: v }) = entry {
-                if k == &key {
-                    return Some(v);
-                }
-            }
+// A piece of rendered output, borrowed from a template or a value, or a character reference or
+// number made on the spot
+pub enum Piece<'a> {
+    Bytes(&'a [u8]),
+    Reference(ByteString<6>),
+    Number(ByteString<20>),
+}
+
+impl<'a> Piece<'a> {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Piece::Bytes(bytes) => bytes,
+            Piece::Reference(reference) => reference.as_bytes(),
+            Piece::Number(number) => number.as_bytes(),
         }
-        None
     }
 }
 
+// A value in pieces, runs of safe bytes as they are and the rest as character references.
+// Bytes of multi-byte UTF-8 characters are always safe and pass through untouched.
+pub struct Escaped<'a> {
+    value: &'a [u8],
+    context: Escape,
+}
 
-pub fn replace<const SIZE: usize>(template: &mut [u8; SIZE], placeholder: &str, value: &str) {
-    let placeholder_bytes = placeholder.as_bytes();
-    let value_bytes = value.as_bytes();
-    let mut i = 0;
+pub fn escaped(value: &[u8], context: Escape) -> Escaped<'_> {
+    Escaped { value, context }
+}
 
-    while i < SIZE {
-        if template[i..].starts_with(placeholder_bytes) {
-            let diff = placeholder_bytes.len() as isize - value_bytes.len() as isize;
+impl<'a> Iterator for Escaped<'a> {
+    type Item = Piece<'a>;
 
-            // Replace placeholder with value
-            for (j, &b) in value_bytes.iter().enumerate() {
-                if i + j < SIZE {
-                    template[i + j] = b;
-                }
-            }
+    fn next(&mut self) -> Option<Piece<'a>> {
+        if self.value.is_empty() {
+            return None;
+        }
 
-            if diff > 0 {
-                // Shift left if replacement is shorter
-                let start = i + value_bytes.len();
-                for j in start..SIZE - diff as usize {
-                    template[j] = template[j + diff as usize];
-                }
-                template[SIZE - diff as usize..].fill(0);
-            } else if diff < 0 {
-                // Shift right if replacement is longer
-                let shift_by = (-diff) as usize; // Make it a positive usize
-                if i + placeholder_bytes.len() + shift_by < SIZE {
-                    for j in (i + placeholder_bytes.len()..SIZE - shift_by).rev() {
-                        template[j + shift_by] = template[j];
-                    }
-                } else {
-                    // Not enough space to expand, break from loop
-                    break;
-                }
+        let context = self.context;
+        match self.value.iter().position(|&byte| character_reference(byte, context).is_some()) {
+            Some(0) => {
+                let reference = character_reference(self.value[0], context)?;
+                self.value = &self.value[1..];
+                Some(Piece::Reference(reference))
+            }
+            Some(end) => {
+                let (run, rest) = self.value.split_at(end);
+                self.value = rest;
+                Some(Piece::Bytes(run))
             }
+            None => {
+                let run = self.value;
+                self.value = &[];
+                Some(Piece::Bytes(run))
+            }
+        }
+    }
+}
 
-            // Move index to the end of the replacement
-            i += value_bytes.len();
-            if diff < 0 {
-                i += (-diff) as usize; // Adjust for longer replacements
+pub fn escape<F: FnMut(&[u8])>(value: &[u8], context: Escape, mut out: F) {
+    for piece in escaped(value, context) {
+        out(piece.as_bytes());
+    }
+}
+
+pub fn write_escaped<S: Sink>(out: &mut S, value: &[u8], context: Escape) -> Result<(), S::Error> {
+    for piece in escaped(value, context) {
+        out.write_bytes(piece.as_bytes())?;
+    }
+    Ok(())
+}
+
+pub async fn write_escaped_async<W: AsyncWrite>(out: &mut W, value: &[u8], context: Escape) -> Result<(), W::Error> {
+    for piece in escaped(value, context) {
+        out.write_all(piece.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn character_reference(byte: u8, context: Escape) -> Option<ByteString<6>> {
+    match context {
+        Escape::Raw => None,
+        Escape::Html => match byte {
+            b'&' => Some(ByteString::new(b"&amp;")),
+            b'<' => Some(ByteString::new(b"&lt;")),
+            b'>' => Some(ByteString::new(b"&gt;")),
+            b'"' => Some(ByteString::new(b"&quot;")),
+            b'\'' => Some(ByteString::new(b"&#x27;")),
+            _ => None,
+        },
+        Escape::Attribute => {
+            if byte >= 0x80 || byte.is_ascii_alphanumeric() || matches!(byte, b',' | b'.' | b'-' | b'_') {
+                return None;
             }
-        } else {
-            i += 1;
+
+            const HEX: &[u8; 16] = b"0123456789ABCDEF";
+            Some(ByteString::new(&[b'&', b'#', b'x', HEX[(byte >> 4) as usize], HEX[(byte & 0x0f) as usize], b';']))
         }
     }
 }
 
-pub fn process_if<const SIZE: usize>(template: &mut [u8; SIZE], condition: bool, if_placeholder: &str, if_end_placeholder: &str, else_placeholder: Option<&str>, else_end_placeholder: Option<&str>) {
-    if condition {
-        // Replace if-placeholder with empty and remove if-end-placeholder
-        replace(template, if_placeholder, "");
-        replace(template, if_end_placeholder, "");
+// The next `{{..}}` or `{{{..}}}` tag: where it starts, where it ends, whether it is raw and
+// what it holds
+fn next_tag(template: &[u8]) -> Option<(usize, usize, bool, &[u8])> {
+    let start = template.windows(2).position(|window| window == b"{{")?;
+    let raw = template[start..].starts_with(b"{{{");
+    let (open, close): (usize, &[u8]) = if raw { (3, b"}}}") } else { (2, b"}}") };
+    let end = start + open + template[start + open..].windows(close.len()).position(|window| window == close)?;
+    Some((start, end + close.len(), raw, template[start + open..end].trim_ascii()))
+}
 
-        // Remove entire else block if else placeholders are provided
-        if let (Some(else_start), Some(else_end)) = (else_placeholder, else_end_placeholder) {
-            remove_block(template, else_start, else_end);
+// Splits a template right after a block's opening tag into the block's body and what follows
+// its closing tag
+fn split_block(template: &[u8]) -> (&[u8], &[u8]) {
+    let mut depth = 0;
+    let mut rest = template;
+
+    while let Some((start, end, raw, tag)) = next_tag(rest) {
+        let body = &template[..template.len() - rest.len() + start];
+        rest = &rest[end..];
+        if raw || tag.starts_with(b"#extends") {
+            continue;
         }
-    } else {
-        // Remove entire if block
-        remove_block(template, if_placeholder, if_end_placeholder);
-
-        // Process else block if else placeholders are provided
-        if let (Some(else_start), Some(else_end)) = (else_placeholder, else_end_placeholder) {
-            replace(template, else_start, "");
-            replace(template, else_end, "");
+        if tag.starts_with(b"#") {
+            depth += 1;
+        } else if tag.starts_with(b"/") {
+            if depth == 0 {
+                return (body, rest);
+            }
+            depth -= 1;
         }
     }
+    (template, b"")
 }
 
-fn remove_block<const SIZE: usize>(template: &mut [u8; SIZE], start_placeholder: &str, end_placeholder: &str) {
-    let start_pos = find_position(template, start_placeholder);
-    let end_pos = find_position(template, end_placeholder);
+// Holds a runtime template to the rules build.rs has for compiled ones: every block opens with a
+// known tag and is closed by `{{/kind}}` or `{{/kind name}}` of that same block. Layouts and
+// their blocks are only for compiled templates.
+pub fn check(template: &[u8]) -> Result<(), &'static str> {
+    let mut open: [(&[u8], &[u8]); MAX_BLOCK_DEPTH] = [(b"", b""); MAX_BLOCK_DEPTH];
+    let mut depth = 0;
+    let mut rest = template;
+
+    while let Some((_, end, raw, tag)) = next_tag(rest) {
+        rest = &rest[end..];
+        if raw {
+            continue;
+        }
 
-    if let (Some(start), Some(end)) = (start_pos, end_pos) {
-        // Calculate length to remove
-        let length_to_remove = end + end_placeholder.len() - start;
+        if let Some(closing) = tag.strip_prefix(b"/") {
+            if depth == 0 {
+                return Err("Closing tag closes nothing");
+            }
+            depth -= 1;
+            let (kind, name) = open[depth];
+            match closing.strip_prefix(kind).map(|closed| closed.trim_ascii()) {
+                Some(closed) if closed.is_empty() || closed == name => {}
+                _ => return Err("Closing tag doesn't match its block"),
+            }
+            continue;
+        }
 
-        // Shift the array to remove the block
-        if start + length_to_remove < SIZE {
-            template.copy_within(start + length_to_remove.., start);
+        if !tag.starts_with(b"#") {
+            continue;
+        }
+        let kind: &[u8] = [&b"if"[..], b"else", b"for"]
+            .into_iter()
+            .find(|kind| tag[1..].starts_with(kind) && tag.get(kind.len() + 1) == Some(&b' '))
+            .ok_or("Unknown block tag")?;
+        let name = tag[kind.len() + 1..].trim_ascii();
+        if kind == b"for" && !name.windows(4).any(|window| window == b" in ") {
+            return Err("Expected {{#for item in list}}");
+        }
+        if depth == MAX_BLOCK_DEPTH {
+            return Err("Blocks nest too deep");
         }
+        open[depth] = (kind, name);
+        depth += 1;
+    }
 
-        // Zero out the shifted part
-        let new_len = SIZE - length_to_remove;
-        template[new_len..].fill(0);
+    match depth {
+        0 => Ok(()),
+        _ => Err("Block is never closed"),
     }
 }
 
-fn find_position<const SIZE: usize>(template: &[u8; SIZE], placeholder: &str) -> Option<usize> {
-    let placeholder_bytes = placeholder.as_bytes();
-    template.windows(placeholder_bytes.len()).position(|window| window == placeholder_bytes)
+#[derive(Copy, Clone)]
+struct Loop<'a> {
+    item: &'a [u8],
+    items: &'a [Context<'a>],
+    index: usize,
+    body: &'a [u8],
 }
 
-fn remove_marked_sections<const SIZE: usize>(template: &mut [u8; SIZE]) {
-    // Find and remove "REMOVE" marked sections
-    replace(template, "REMOVE", "");
+// Streams a template in a single pass, filling `{{name}}` placeholders from the context escaped
+// for where they sit and `{{{name}}}` ones as they are. The blocks are the ones build.rs knows:
+//
+//   {{#if name}}..{{/if name}}       rendered when the value is truthy
+//   {{#else name}}..{{/else name}}   rendered when it isn't
+//   {{#for item in items}}..{{/for item in items}}
+//                                    repeated for every context in the list, with
+//                                    `{{item.name}}` reaching into it
+//
+// Names without a value render as nothing and their blocks as false. The template is checked
+// before it is used, and one that is malformed renders nothing but RenderError::Template rather
+// than guessing what was meant.
+pub struct Renderer<'a> {
+    template: &'a [u8],
+    context: Context<'a>,
+    loops: [Option<Loop<'a>>; MAX_LOOP_DEPTH],
+    depth: usize,
+    in_tag: bool,
+    value: Option<Escaped<'a>>,
+    error: Option<&'static str>,
 }
 
-pub fn process_for<const TEMPLATE_SIZE: usize>(
-    template: &mut [u8; TEMPLATE_SIZE],
-    loop_var: &str,
-    item_list: &str,
-    items: &[StaticDict])
-{
-    // Construct start tag
-    let mut start_tag = ByteString::<128>::new(b"");
-    start_tag.append(b"{{#for ");
-    start_tag.append(loop_var.as_bytes());
-    start_tag.append(b" in ");
-    start_tag.append(item_list.as_bytes());
-    start_tag.append(b"}}");
-    let start_tag_str = core::str::from_utf8(&start_tag.as_bytes()).unwrap_or("");
-
-    // Construct end tag
-    let mut end_tag = ByteString::<128>::new(b"{{/for ");
-    end_tag.append(loop_var.as_bytes());
-    end_tag.append(b" in ");
-    end_tag.append(item_list.as_bytes());
-    end_tag.append(b"}}");
-    let end_tag_str = core::str::from_utf8(&end_tag.as_bytes()).unwrap_or("");
-
-
-    let start_pos = find_position(template, &start_tag_str);
-    let end_pos = find_position(template, &end_tag_str);
-
-    if let (Some(start), Some(end)) = (start_pos, end_pos) {
-        let loop_content_length = end - start - start_tag.len();
-        let mut loop_content_buffer = [0u8; TEMPLATE_SIZE];
-        loop_content_buffer[..loop_content_length].copy_from_slice(&template[start + start_tag.len()..end]);
-
-        // Clear the loop area in the template
-        for i in start..end + end_tag.len() {
-            template[i] = 0;
+impl<'a> Renderer<'a> {
+    pub fn new(template: &'a str, context: Context<'a>) -> Renderer<'a> {
+        Renderer {
+            template: template.as_bytes(),
+            context,
+            loops: [None; MAX_LOOP_DEPTH],
+            depth: 0,
+            in_tag: false,
+            value: None,
+            error: check(template.as_bytes()).err(),
+        }
+    }
+
+    pub fn render<S: Sink>(mut self, out: &mut S) -> Result<(), RenderError<S::Error>> {
+        for piece in self.by_ref() {
+            out.write_bytes(piece.as_bytes()).map_err(RenderError::Output)?;
         }
+        self.error.map_or(Ok(()), |error| Err(RenderError::Template(error)))
+    }
+
+    fn text(&mut self, length: usize) -> Piece<'a> {
+        let (text, rest) = self.template.split_at(length);
+        self.template = rest;
 
-        let mut result_index = start;
+        if let Some(position) = text.iter().rposition(|&b| b == b'<' || b == b'>') {
+            self.in_tag = text[position] == b'<';
+        }
+        Piece::Bytes(text)
+    }
 
-        // Iterate over items and replace loop variable with each item
-        for item_dict in items {
-            let mut loop_iteration = [0; TEMPLATE_SIZE];
-            loop_iteration[..loop_content_length].copy_from_slice(&loop_content_buffer[..loop_content_length]);
+    // `item.name` looks in the innermost loop over `item`, anything else in the context
+    fn lookup(&self, name: &[u8]) -> Option<Value<'a>> {
+        if let Some(dot) = name.iter().position(|&b| b == b'.') {
+            let (item, field) = (&name[..dot], &name[dot + 1..]);
+            let current = self.loops[..self.depth].iter().rev().flatten().find(|l| l.item == item);
+            if let Some(current) = current {
+                return current.items[current.index].get(core::str::from_utf8(field).ok()?);
+            }
+        }
+        self.context.get(core::str::from_utf8(name).ok()?)
+    }
+
+    // Drops everything up to and including the tag closing the block just opened
+    fn skip_block(&mut self) {
+        self.template = split_block(self.template).1;
+    }
+
+    fn open_loop(&mut self, header: &'a [u8]) {
+        let split = header.windows(4).position(|window| window == b" in ");
+        let items = match split.map(|at| self.lookup(header[at + 4..].trim_ascii())) {
+            Some(Some(Value::List(items))) => items,
+            _ => &[],
+        };
+
+        if self.depth == MAX_LOOP_DEPTH {
+            self.error = Some("Loops nest too deep");
+            return;
+        }
+        if items.is_empty() {
+            self.skip_block();
+            return;
+        }
+
+        let item = header[..split.unwrap_or(0)].trim_ascii();
+        self.loops[self.depth] = Some(Loop { item, items, index: 0, body: self.template });
+        self.depth += 1;
+    }
+
+    // Goes round again from the top of the body, or carries on past the loop after the last item
+    fn close_loop(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+
+        if let Some(current) = self.loops[self.depth - 1].as_mut() {
+            current.index += 1;
+            if current.index < current.items.len() {
+                self.template = current.body;
+                return;
+            }
+        }
+        self.depth -= 1;
+        self.loops[self.depth] = None;
+    }
+}
+
+impl<'a> Iterator for Renderer<'a> {
+    type Item = Piece<'a>;
+
+    fn next(&mut self) -> Option<Piece<'a>> {
+        loop {
+            if self.error.is_some() {
+                return None;
+            }
+
+            if let Some(piece) = self.value.as_mut().and_then(|value| value.next()) {
+                return Some(piece);
+            }
+            self.value = None;
 
-            for i in 0..item_dict.count {
-                if let Some(KeyValuePair { key, value }) = item_dict.entries[i] {
-                    let mut placeholder = ByteString::<128>::new(b"{{");
-                    placeholder.append(loop_var.as_bytes());
-                    placeholder.append(b".");
-                    placeholder.append(key.as_bytes());
-                    placeholder.append(b"}}");
-                    let placeholder_str = core::str::from_utf8(&placeholder.as_bytes()).unwrap_or("");
+            if self.template.is_empty() {
+                return None;
+            }
 
-                    // If format! is not available, construct the placeholder manually
-                    replace(&mut loop_iteration, placeholder_str, value);
+            let (end, raw, tag) = match next_tag(self.template) {
+                Some((0, end, raw, tag)) => (end, raw, tag),
+                Some((start, ..)) => return Some(self.text(start)),
+                // Not a placeholder after all
+                None => return Some(self.text(self.template.len())),
+            };
+            self.template = &self.template[end..];
+
+            if !raw && tag.starts_with(b"#") {
+                if let Some(name) = tag.strip_prefix(b"#if ") {
+                    if !self.lookup(name.trim_ascii()).is_some_and(|value| value.is_truthy()) {
+                        self.skip_block();
+                    }
+                } else if let Some(name) = tag.strip_prefix(b"#else ") {
+                    if self.lookup(name.trim_ascii()).is_some_and(|value| value.is_truthy()) {
+                        self.skip_block();
+                    }
+                } else if let Some(header) = tag.strip_prefix(b"#for ") {
+                    self.open_loop(header);
                 }
+                continue;
             }
 
-            // Append the processed loop_iteration back to the template
-            for &byte in loop_iteration.iter().take_while(|&&b| b != 0) {
-                if result_index < TEMPLATE_SIZE {
-                    template[result_index] = byte;
-                    result_index += 1;
+            if !raw && tag.starts_with(b"/") {
+                if tag.starts_with(b"/for") {
+                    self.close_loop();
                 }
+                continue;
             }
-        }
 
-        // Manually shift the rest of the template accordingly
-        let shift_start = result_index;
-        let shift_length = TEMPLATE_SIZE - end - end_tag.len();
-        for i in 0..shift_length {
-            if end + end_tag.len() + i < TEMPLATE_SIZE {
-                template[shift_start + i] = template[end + end_tag.len() + i];
+            let context = if raw {
+                Escape::Raw
+            } else if self.in_tag {
+                Escape::Attribute
+            } else {
+                Escape::Html
+            };
+
+            match self.lookup(tag) {
+                Some(Value::Str(value)) => self.value = Some(escaped(value, context)),
+                Some(Value::Int(value)) => {
+                    let mut number = ByteString::<20>::new(b"");
+                    let _ = write!(number, "{}", value);
+                    return Some(Piece::Number(number));
+                }
+                Some(Value::Bool(true)) => return Some(Piece::Bytes(b"true")),
+                Some(Value::Bool(false)) => return Some(Piece::Bytes(b"false")),
+                Some(Value::List(_)) | None => {}
             }
         }
-
-        // Zero out the remaining part of the template
-        template[shift_start + shift_length..].fill(0);
     }
 }
 
+pub fn render<S: Sink>(template: &str, context: &Context, out: &mut S) -> Result<(), RenderError<S::Error>> {
+    Renderer::new(template, *context).render(out)
+}
 
-#[macro_export]
-macro_rules! include_str_checked {
-    ($file:expr, $max_len:expr) => {{
-        const CONTENT: &str = include_str!($file);
-        const LENGTH: usize = CONTENT.len();
-
-        // Compile-time length check
-        let _ = [(); $max_len - LENGTH]; // Will fail if LENGTH > $max_len
-
-        CONTENT
-    }};
+// Render functions build.rs generates from the files under src/templates
+pub mod compiled {
+    include!(concat!(env!("OUT_DIR"), "/templates.rs"));
 }
 
 #[cfg(test)]
@@ -243,42 +477,159 @@ mod tests {
     use super::*; // Import your http module functions
 
     #[test]
-    fn test_template() { // Template string with full if-else structure
-        let template_str = b"Hello, {{name}}. {{#if condition}}Welcome!{{/if}}{{#else}}Goodbye.{{/else}} {{#if exit}}Nope{{/if2}} {{#for person in people}}Name: {{person.name}}, Age: {{person.age}}, {{/for person in people}}";
-
-        // Initialize a buffer with zeros and copy the template string into it
-        let mut template: [u8; TEMPLATE_SIZE] = [0; TEMPLATE_SIZE];
-        template[..template_str.len()].copy_from_slice(template_str);
-
-
-        // Replace placeholders
-        replace(&mut template, "{{name}}", "Alice");
+    fn test_template() {
+        let template = "Hello, {{name}}. {{#if condition}}Welcome!{{/if}}{{#else condition}}Goodbye.{{/else}} \
+                        {{#if exit}}Nope{{/if exit}}{{#for person in people}}Name: {{person.name}}, Age: {{person.age}}, {{/for person in people}}";
+
+        let alice = [("name", Value::Str(b"Alice")), ("age", Value::Int(30))];
+        let bob = [("name", Value::Str(b"Bob")), ("age", Value::Int(25))];
+        let people = [Context::new(&alice), Context::new(&bob)];
+        let entries = [
+            ("name", Value::Str(b"Alice")),
+            ("condition", Value::Bool(true)),
+            ("people", Value::List(&people)),
+        ];
+
+        let mut output = ByteString::<256>::new(b"");
+        assert_eq!(render(template, &Context::new(&entries), &mut output), Ok(()));
+        assert_eq!(output.as_bytes(), b"Hello, Alice. Welcome! Name: Alice, Age: 30, Name: Bob, Age: 25, ");
+    }
 
+    #[test]
+    fn test_template_blocks() {
+        let template = "{{#for row in rows}}[{{#for cell in row.cells}}{{cell.n}}{{#if cell.last}};{{/if}}{{/for}}]{{/for}}\
+                        {{#else rows}}none{{/else}}{{#if count}}{{count}}{{/if}}{{#if missing}}x{{#if count}}y{{/if}}z{{/if}}";
+
+        let (one, two) = ([("n", Value::Int(1))], [("n", Value::Int(-2)), ("last", Value::Bool(true))]);
+        let cells = [Context::new(&one), Context::new(&two)];
+        let row = [("cells", Value::List(&cells))];
+        let empty = [("cells", Value::List(&[]))];
+        let rows = [Context::new(&row), Context::new(&empty), Context::new(&row)];
+        let entries = [("rows", Value::List(&rows)), ("count", Value::Int(3))];
+
+        let mut output = ByteString::<64>::new(b"");
+        assert_eq!(render(template, &Context::new(&entries), &mut output), Ok(()));
+        assert_eq!(output.as_bytes(), b"[1-2;][][1-2;]3");
+
+        let entries = [("rows", Value::List(&[])), ("count", Value::Int(0))];
+        let mut output = ByteString::<64>::new(b"");
+        assert_eq!(render(template, &Context::new(&entries), &mut output), Ok(()));
+        assert_eq!(output.as_bytes(), b"none");
+    }
 
+    #[test]
+    fn test_template_check() {
+        assert_eq!(check(b"{{#if a}}{{#for x in xs}}{{/for x in xs}}{{/if}}{{#else a}}{{/else a}}"), Ok(()));
+        assert_eq!(check(b"{{#extends base}}{{#block title}}{{/block title}}"), Err("Unknown block tag"));
+        assert_eq!(check(b"{{#if a}}{{/for}}"), Err("Closing tag doesn't match its block"));
+        assert_eq!(check(b"{{#if a}}{{/if b}}"), Err("Closing tag doesn't match its block"));
+        assert_eq!(check(b"{{/if}}"), Err("Closing tag closes nothing"));
+        assert_eq!(check(b"{{#if a}}"), Err("Block is never closed"));
+        assert_eq!(check(b"{{#unless a}}{{/unless}}"), Err("Unknown block tag"));
+        assert_eq!(check(b"{{#for xs}}{{/for}}"), Err("Expected {{#for item in list}}"));
+
+        // A malformed page renders nothing
+        let entries = [("a", Value::Bool(true))];
+        let mut output = ByteString::<64>::new(b"");
+        let result = render("{{#if a}}x{{/for}}", &Context::new(&entries), &mut output);
+        assert_eq!(result, Err(RenderError::Template("Closing tag doesn't match its block")));
+        assert_eq!(output.as_bytes(), b"");
 
-        // Process if-else condition (true condition in this case)
-        process_if(&mut template, true, "{{#if condition}}", "{{/if}}", Some("{{#else}}"), Some("{{/else}}"));
+    }
 
-        process_if(&mut template, false, "{{#if exit}}", "{{/if2}}", None, None);
+    #[test]
+    fn test_template_loop_depth() {
+        let one = [Context::new(&[])];
+        let entries = [("xs", Value::List(&one))];
+        let context = Context::new(&entries);
+
+        // Loops past MAX_LOOP_DEPTH end the render with an error instead of leaving a part out
+        let page = "{{#for a in xs}}a{{#for b in xs}}b{{#for c in xs}}c{{#for d in xs}}d\
+                    {{#for e in xs}}e{{/for}}{{/for}}{{/for}}{{/for}}{{/for}}";
+        let mut output = ByteString::<64>::new(b"");
+        assert_eq!(render(page, &context, &mut output), Err(RenderError::Template("Loops nest too deep")));
+        assert_eq!(output.as_bytes(), b"abcd");
+    }
 
-        // Process the for-loop
-        let mut dict1 = StaticDict::new();
-        dict1.insert("name", "Alice");
-        dict1.insert("age", "30");
+    #[test]
+    fn test_escape() {
+        let mut output = ByteString::<64>::new(b"");
+        escape("caf\u{e9} a<b".as_bytes(), Escape::Html, |piece| output.append(piece));
+        assert_eq!(output.as_bytes(), "caf\u{e9} a&lt;b".as_bytes());
+
+        let mut output = ByteString::<64>::new(b"");
+        escape(b"a b=c", Escape::Raw, |piece| output.append(piece));
+        assert_eq!(output.as_bytes(), b"a b=c");
+    }
 
-        let mut dict2 = StaticDict::new();
-        dict2.insert("name", "Bob");
-        dict2.insert("age", "25");
+    #[test]
+    fn test_compiled_template() {
+        let context = compiled::login::Context {
+            csrf_token: b"abc",
+            csrf_error: false,
+            username: b"\"><script>",
+            login_error: true,
+        };
+        let mut output = [0u8; 4096];
+        let mut sink: &mut [u8] = &mut output;
+        assert_eq!(embassy_futures::block_on(compiled::login::render_async(&context, &mut sink)), Ok(()));
+
+        let remaining = sink.len();
+        let html = core::str::from_utf8(&output[..output.len() - remaining]).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.ends_with("</html>\n"));
+        assert!(html.contains("name=\"csrf_token\" value=\"abc\""));
+        assert!(html.contains("value=\"&#x22;&#x3E;&#x3C;script&#x3E;\""));
+        assert!(html.contains("Wrong username or password"));
+        assert!(!html.contains("The form expired"));
+        assert!(!html.contains("{{"));
+    }
 
-        let items = [dict1, dict2];
-        process_for(&mut template, "person", "people", &items);
+    #[test]
+    fn test_compiled_layout() {
+        let context = compiled::sign_up::Context {
+            csrf_token: b"abc",
+            csrf_error: false,
+            signup_success: false,
+            username: b"ann",
+            user_error: true,
+            username_taken_error: false,
+            store_full_error: false,
+            password_match_error: false,
+            password_error: false,
+            password_confirm_error: false,
+        };
+        let mut output = [0u8; 8192];
+        let mut sink: &mut [u8] = &mut output;
+        assert_eq!(embassy_futures::block_on(compiled::sign_up::render_async(&context, &mut sink)), Ok(()));
+
+        let remaining = sink.len();
+        let html = core::str::from_utf8(&output[..output.len() - remaining]).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>Signup Form</title>"));
+        assert!(html.contains("htmx.org"));
+        assert!(html.contains("value=\"ann\""));
+        assert!(html.contains("Please enter a username"));
+        assert!(html.contains("name=\"csrf_token\" value=\"abc\""));
+        assert!(html.ends_with("</html>\n"));
+        assert!(!html.contains("{{"));
+    }
 
+    #[test]
+    fn test_render() {
+        let template = "<a href=\"/x?n={{name}}\">{{name}}</a>{{{html}}}{{missing}} {{unclosed";
+        let entries = [("name", Value::Str(b"a&b")), ("html", Value::Str(b"<br>"))];
+        let context = Context::new(&entries);
+
+        let mut output = ByteString::<128>::new(b"");
+        assert_eq!(render(template, &context, &mut output), Ok(()));
+        assert_eq!(output.as_bytes(), b"<a href=\"/x?n=a&#x26;b\">a&amp;b</a><br> {{unclosed");
+
+        // Running out of room is an error rather than a quietly cut off page
+        let mut output = ByteString::<16>::new(b"");
+        assert_eq!(render(template, &context, &mut output), Err(RenderError::Output("Output too long")));
+        assert_eq!(output.len(), 16);
+    }
 
-        // Convert the buffer to a string and print for verification
-        let result = core::str::from_utf8(&template).unwrap_or("<invalid UTF-8>");
-        // println!("Actual: {}", result);
 
-        // Assert to check if the result matches expected output
-        assert_eq!(result.trim_end_matches(char::from(0)), "Hello, Alice. Welcome!  Name: Alice, Age: 30, Name: Bob, Age: 25, ");
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,117 @@
+// application/x-www-form-urlencoded decoding and encoding, used for query strings and POST bodies
+
+// Decodes `+` as a space and `%XX` escapes as bytes. A `%` that isn't followed by two hex digits
+// is kept as is. Stops early if the buffer fills up, returns the number of bytes written.
+pub fn url_decode(input: &[u8], buffer: &mut [u8]) -> usize {
+    let mut index = 0;
+    let mut buffer_index = 0;
+
+    while index < input.len() && buffer_index < buffer.len() {
+        let byte = match input[index] {
+            b'+' => {
+                index += 1;
+                b' '
+            }
+            b'%' => match (input.get(index + 1).and_then(|&b| hex_digit(b)), input.get(index + 2).and_then(|&b| hex_digit(b))) {
+                (Some(high), Some(low)) => {
+                    index += 3;
+                    (high << 4) | low
+                }
+                _ => {
+                    index += 1;
+                    b'%'
+                }
+            },
+            byte => {
+                index += 1;
+                byte
+            }
+        };
+
+        buffer[buffer_index] = byte;
+        buffer_index += 1;
+    }
+
+    buffer_index
+}
+
+// Leaves unreserved characters alone, turns spaces into `+` and escapes everything else as `%XX`.
+// Never writes a partial escape, returns the number of bytes written.
+pub fn url_encode(input: &[u8], buffer: &mut [u8]) -> usize {
+    const HEX: &[u8] = b"0123456789ABCDEF";
+
+    let mut buffer_index = 0;
+
+    for &byte in input {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' || byte == b'.' || byte == b'~' {
+            if buffer_index + 1 > buffer.len() {
+                break;
+            }
+            buffer[buffer_index] = byte;
+            buffer_index += 1;
+        } else if byte == b' ' {
+            if buffer_index + 1 > buffer.len() {
+                break;
+            }
+            buffer[buffer_index] = b'+';
+            buffer_index += 1;
+        } else {
+            if buffer_index + 3 > buffer.len() {
+                break;
+            }
+            buffer[buffer_index] = b'%';
+            buffer[buffer_index + 1] = HEX[(byte >> 4) as usize];
+            buffer[buffer_index + 2] = HEX[(byte & 0x0F) as usize];
+            buffer_index += 3;
+        }
+    }
+
+    buffer_index
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_decode() {
+        let mut buffer = [0u8; 64];
+
+        let n = url_decode(b"a+b%20c", &mut buffer);
+        assert_eq!(&buffer[..n], b"a b c");
+
+        let n = url_decode(b"caf%C3%A9", &mut buffer);
+        assert_eq!(&buffer[..n], "café".as_bytes());
+
+        // Broken escapes are passed through instead of being dropped
+        let n = url_decode(b"100%+%zz%4", &mut buffer);
+        assert_eq!(&buffer[..n], b"100% %zz%4");
+    }
+
+    #[test]
+    fn test_url_encode_round_trip() {
+        let mut encoded = [0u8; 64];
+        let mut decoded = [0u8; 64];
+
+        let value = "my file & café.txt".as_bytes();
+        let encoded_length = url_encode(value, &mut encoded);
+        assert_eq!(&encoded[..encoded_length], b"my+file+%26+caf%C3%A9.txt");
+
+        let decoded_length = url_decode(&encoded[..encoded_length], &mut decoded);
+        assert_eq!(&decoded[..decoded_length], value);
+
+        // Escapes are never cut in half when the buffer runs out
+        let mut small = [0u8; 4];
+        let n = url_encode(b"ab&c", &mut small);
+        assert_eq!(&small[..n], b"ab");
+    }
+}
@@ -1,11 +1,59 @@
+use rand::RngCore;
+use crate::hmac::{constant_time_eq, pbkdf2_sha256};
 use crate::kv::Serializable;
 
+// Raising this re-hashes each password at its owner's next login. Every round is two SHA-256
+// blocks done in plain Rust, the sha2 asm feature has nothing for the Cortex-M0+. Time a login
+// on the board before changing it.
+pub const PASSWORD_ITERATIONS: u32 = 10_000;
+pub const SALT_LENGTH: usize = 16;
+
+// Password checks PasswordThrottle lets through per window: from one client address, for one
+// username, and for everyone together so the device isn't kept hashing
+pub const MAX_CLIENT_CHECKS: u32 = 5;
+pub const MAX_USERNAME_CHECKS: u32 = 5;
+pub const MAX_PASSWORD_CHECKS: u32 = 30;
+pub const THROTTLE_WINDOW: u64 = 10;
+// Client addresses and usernames tracked at once, the stalest makes room for a new one
+const THROTTLE_SLOTS: usize = 16;
+
+// Records written before passwords were hashed stop after the role byte
+const LEGACY_RECORD_LENGTH: usize = 67;
+pub const RECORD_LENGTH: usize = LEGACY_RECORD_LENGTH + SALT_LENGTH + 4;
+
+// Each role can do everything the ones before it can
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+#[repr(u8)]
+pub enum Role {
+    Guest = 0,
+    User = 1,
+    Admin = 2,
+}
+
+impl Role {
+    // Unknown values, say from a newer firmware, fall back to the least privileged role
+    pub fn from_u8(value: u8) -> Role {
+        match value {
+            1 => Role::User,
+            2 => Role::Admin,
+            _ => Role::Guest,
+        }
+    }
+
+    pub fn allows(self, required: Role) -> bool {
+        self >= required
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct User {
     pub(crate) id: u16,
     pub(crate) username: [u8; 32],
+    // PBKDF2-HMAC-SHA256 of the password, or the zero padded plaintext when iterations is 0
     pub(crate) password: [u8; 32],
-    pub(crate) role: u8,
+    pub(crate) role: Role,
+    pub(crate) salt: [u8; SALT_LENGTH],
+    pub(crate) iterations: u32,
 }
 
 impl User {
@@ -15,26 +63,148 @@ impl User {
             id: 0,
             username: Default::default(),
             password: Default::default(),
-            role: 0,
+            role: Role::User,
+            salt: Default::default(),
+            iterations: 0,
         }
     }
 
+    // Usernames are stored zero padded to 32 bytes
+    pub fn has_username(&self, username: &[u8]) -> bool {
+        username.len() <= 32 && self.username[..username.len()] == *username && self.username[username.len()..].iter().all(|&b| b == 0)
+    }
+
+    // Hashes the password under a fresh salt with the current iteration count
+    pub async fn set_password<R: RngCore>(&mut self, rng: &mut R, password: &[u8]) {
+        rng.fill_bytes(&mut self.salt);
+        self.iterations = PASSWORD_ITERATIONS;
+        pbkdf2_sha256(password, &self.salt, self.iterations, &mut self.password).await;
+    }
+
+    pub async fn check_password(&self, password: &[u8]) -> bool {
+        let mut derived = [0u8; 32];
+
+        if self.iterations == 0 {
+            if password.len() > 32 {
+                return false;
+            }
+            derived[..password.len()].copy_from_slice(password);
+        } else {
+            pbkdf2_sha256(password, &self.salt, self.iterations, &mut derived).await;
+        }
+
+        constant_time_eq(&derived, &self.password)
+    }
+
+    // Checked in place of a user that wasn't found, so an unknown username takes as long as a
+    // wrong password and the timing doesn't give away which accounts exist. No password matches it.
+    pub fn dummy() -> User {
+        User { iterations: PASSWORD_ITERATIONS, ..User::new() }
+    }
+
+    // True once the stored hash was made with other parameters than set_password would use now
+    pub fn needs_rehash(&self) -> bool {
+        self.iterations != PASSWORD_ITERATIONS
+    }
+
     pub fn deserialize(data: &[u8]) -> Option<User> {
-        if data.len() >= 67 {
+        if data.len() >= LEGACY_RECORD_LENGTH {
             let id = u16::from_be_bytes([data[0], data[1]]);
             let mut username = [0u8; 32];
             username.copy_from_slice(&data[2..34]);
             let mut password = [0u8; 32];
             password.copy_from_slice(&data[34..66]);
-            let role = data[66];
+            let role = Role::from_u8(data[66]);
+
+            // A legacy record keeps its plaintext until needs_rehash gets it replaced
+            let mut salt = [0u8; SALT_LENGTH];
+            let mut iterations = 0;
+            if data.len() >= RECORD_LENGTH {
+                salt.copy_from_slice(&data[67..67 + SALT_LENGTH]);
+                iterations = u32::from_be_bytes([data[83], data[84], data[85], data[86]]);
+            }
 
-            Some(User { id, username, password, role })
+            Some(User { id, username, password, role, salt, iterations })
         } else {
             None // Not enough data to deserialize
         }
     }
 }
 
+// Checks counted per key in windows of THROTTLE_WINDOW seconds, each starting at its key's
+// first check: (key, window start, checks)
+struct CheckCounter<K, const N: usize> {
+    slots: [Option<(K, u64, u32)>; N],
+}
+
+impl<K: Copy + PartialEq, const N: usize> CheckCounter<K, N> {
+    fn new() -> Self {
+        CheckCounter { slots: [None; N] }
+    }
+
+    fn checks(&self, key: &K, now: u64) -> u32 {
+        self.slots.iter().flatten()
+            .find(|(slot_key, start, _)| slot_key == key && now < start + THROTTLE_WINDOW)
+            .map_or(0, |(_, _, checks)| *checks)
+    }
+
+    fn count(&mut self, key: K, now: u64) {
+        if let Some((_, start, checks)) = self.slots.iter_mut().flatten().find(|(slot_key, _, _)| *slot_key == key) {
+            if now >= *start + THROTTLE_WINDOW {
+                *start = now;
+                *checks = 0;
+            }
+            *checks += 1;
+            return;
+        }
+
+        // A free slot, else the one whose window started longest ago
+        let slot = self.slots.iter_mut()
+            .min_by_key(|slot| slot.map_or(0, |(_, start, _)| start + 1))
+            .unwrap();
+        *slot = Some((key, now, 1));
+    }
+}
+
+// Hashing is slow on purpose, so without a limit anyone could keep the device busy hashing or
+// guess away at a password. A client address or username that has used up its window's checks
+// is turned away until the window ends, other clients and accounts carry on. The overall limit
+// is only there to bound the work when many addresses take part.
+pub struct PasswordThrottle {
+    everyone: CheckCounter<(), 1>,
+    clients: CheckCounter<[u8; 4], THROTTLE_SLOTS>,
+    usernames: CheckCounter<[u8; 32], THROTTLE_SLOTS>,
+}
+
+impl PasswordThrottle {
+    pub fn new() -> Self {
+        PasswordThrottle {
+            everyone: CheckCounter::new(),
+            clients: CheckCounter::new(),
+            usernames: CheckCounter::new(),
+        }
+    }
+
+    // Counts a check of `username` from `client` at `now`, false when any of the limits is reached
+    pub fn allow(&mut self, now: u64, client: [u8; 4], username: &[u8]) -> bool {
+        // Stored the way User keeps it, longer names can't belong to anyone
+        let mut padded = [0u8; 32];
+        padded[..username.len().min(32)].copy_from_slice(&username[..username.len().min(32)]);
+
+        if self.everyone.checks(&(), now) >= MAX_PASSWORD_CHECKS
+            || self.clients.checks(&client, now) >= MAX_CLIENT_CHECKS
+            || self.usernames.checks(&padded, now) >= MAX_USERNAME_CHECKS
+        {
+            return false;
+        }
+
+        self.everyone.count((), now);
+        self.clients.count(client, now);
+        self.usernames.count(padded, now);
+        true
+    }
+}
+
 impl Serializable for User {
     fn serialize(&self, buffer: &mut [u8]) -> usize {
         let mut cursor = 0;
@@ -52,7 +222,7 @@ impl Serializable for User {
             cursor += 32;
         }
 
-        // Serialize the password
+        // Serialize the password hash
         if buffer.len() >= cursor + 32 {
             buffer[cursor..cursor + 32].copy_from_slice(&self.password);
             cursor += 32;
@@ -60,10 +230,119 @@ impl Serializable for User {
 
         // Serialize the role
         if buffer.len() >= cursor + 1 {
-            buffer[cursor] = self.role;
+            buffer[cursor] = self.role as u8;
             cursor += 1;
         }
 
+        // Serialize the salt and iteration count
+        if buffer.len() >= cursor + SALT_LENGTH + 4 {
+            buffer[cursor..cursor + SALT_LENGTH].copy_from_slice(&self.salt);
+            cursor += SALT_LENGTH;
+            buffer[cursor..cursor + 4].copy_from_slice(&self.iterations.to_be_bytes());
+            cursor += 4;
+        }
+
         cursor // Total bytes written
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_password_hashing() {
+        let mut rng = ChaCha20Rng::from_seed([7; 32]);
+        let mut user = User::new();
+        block_on(user.set_password(&mut rng, b"correct horse battery staple, longer than 32 bytes"));
+
+        assert!(block_on(user.check_password(b"correct horse battery staple, longer than 32 bytes")));
+        assert!(!block_on(user.check_password(b"correct horse battery staple")));
+        assert!(!user.needs_rehash());
+        assert!(!user.password.starts_with(b"correct"));
+
+        // Same password, fresh salt, different hash
+        let mut other = User::new();
+        block_on(other.set_password(&mut rng, b"correct horse battery staple, longer than 32 bytes"));
+        assert_ne!(user.salt, other.salt);
+        assert_ne!(user.password, other.password);
+
+        let mut buffer = [0u8; RECORD_LENGTH];
+        assert_eq!(user.serialize(&mut buffer), RECORD_LENGTH);
+        let loaded = User::deserialize(&buffer).unwrap();
+        assert_eq!(loaded.iterations, PASSWORD_ITERATIONS);
+        assert!(block_on(loaded.check_password(b"correct horse battery staple, longer than 32 bytes")));
+    }
+
+    #[test]
+    fn test_dummy_user() {
+        let dummy = User::dummy();
+        assert_eq!(dummy.iterations, PASSWORD_ITERATIONS);
+        assert!(!block_on(dummy.check_password(b"")));
+        assert!(!block_on(dummy.check_password(b"password")));
+    }
+
+    #[test]
+    fn test_password_throttle() {
+        let (attacker, other) = ([10, 0, 0, 66], [10, 0, 0, 7]);
+        let mut throttle = PasswordThrottle::new();
+        for _ in 0..MAX_CLIENT_CHECKS {
+            assert!(throttle.allow(100, attacker, b"alice"));
+        }
+        // The address is out of checks, for any username, but nobody else is
+        assert!(!throttle.allow(100, attacker, b"bob"));
+        assert!(!throttle.allow(100 + THROTTLE_WINDOW - 1, attacker, b"bob"));
+        assert!(throttle.allow(100, other, b"bob"));
+        assert!(throttle.allow(100 + THROTTLE_WINDOW, attacker, b"bob"));
+
+        // Guessing at one account from many addresses stops at the username's limit
+        let mut throttle = PasswordThrottle::new();
+        for client in 0..MAX_USERNAME_CHECKS as u8 {
+            assert!(throttle.allow(100, [10, 0, 1, client], b"alice"));
+        }
+        assert!(!throttle.allow(100, [10, 0, 2, 1], b"alice"));
+        assert!(throttle.allow(100, [10, 0, 2, 1], b"bob"));
+
+        // Many addresses and usernames together still hit the overall limit
+        let mut throttle = PasswordThrottle::new();
+        for n in 0..MAX_PASSWORD_CHECKS as u8 {
+            assert!(throttle.allow(100, [10, 1, 0, n], &[b'u', n]));
+        }
+        assert!(!throttle.allow(100, [10, 2, 0, 0], b"carol"));
+        assert!(throttle.allow(100 + THROTTLE_WINDOW, [10, 2, 0, 0], b"carol"));
+    }
+
+    #[test]
+    fn test_roles() {
+        assert!(Role::Admin.allows(Role::User));
+        assert!(Role::User.allows(Role::User));
+        assert!(!Role::User.allows(Role::Admin));
+        assert!(!Role::Guest.allows(Role::User));
+
+        assert_eq!(Role::from_u8(Role::Admin as u8), Role::Admin);
+        assert_eq!(Role::from_u8(7), Role::Guest);
+    }
+
+    #[test]
+    fn test_legacy_password() {
+        let mut record = [0u8; LEGACY_RECORD_LENGTH];
+        record[1] = 3;
+        record[2..7].copy_from_slice(b"alice");
+        record[34..40].copy_from_slice(b"secret");
+
+        let mut user = User::deserialize(&record).unwrap();
+        assert!(user.has_username(b"alice"));
+        assert_eq!(user.role, Role::Guest);
+        assert!(block_on(user.check_password(b"secret")));
+        assert!(!block_on(user.check_password(b"secre")));
+        assert!(user.needs_rehash());
+
+        block_on(user.set_password(&mut ChaCha20Rng::from_seed([1; 32]), b"secret"));
+        assert!(!user.needs_rehash());
+        assert!(block_on(user.check_password(b"secret")));
+        assert!(!user.password.starts_with(b"secret"));
+    }
+}